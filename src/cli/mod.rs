@@ -0,0 +1,130 @@
+use std::path::{Path, PathBuf};
+
+use crate::constants::{DEFAULT_IMPORT_PATTERN, DEFAULT_TILE_PATTERN, DEFAULT_TILE_SIZE};
+use crate::export::{self, ExportFormat, FocusMapOptions};
+use crate::import::{self, ImportOptions};
+
+const USAGE: &str = "Usage:
+    focus-annotator                      start the annotation tool
+    focus-annotator import <folder> [--pattern <regex>] [--radius <tiles>] [--output <dataset.json>]
+    focus-annotator tile <folder> [--size <pixels>] [--overlap <pixels>] [--pattern <regex>] [--radius <tiles>] [--output <dataset.json>]
+    focus-annotator focus-maps <dataset.json> [--format png|tiff] [--guided] [--output <folder>]";
+
+fn option_value(args: &[String], name: &str) -> Option<String> {
+    args.iter()
+        .position(|x| x == name)
+        .and_then(|index| args.get(index + 1))
+        .cloned()
+}
+
+fn import(args: &[String], tiling: bool) -> i32 {
+    let dir = match args.first().filter(|x| !x.starts_with("--")) {
+        Some(dir) => PathBuf::from(dir),
+        None => {
+            eprintln!("{}", USAGE);
+            return 2;
+        }
+    };
+    let default_pattern = if tiling {
+        DEFAULT_TILE_PATTERN
+    } else {
+        DEFAULT_IMPORT_PATTERN
+    };
+    let mut options =
+        ImportOptions::new(option_value(args, "--pattern").unwrap_or(default_pattern.to_string()));
+    match option_value(args, "--radius").map_or(Ok(1), |x| x.parse::<usize>()) {
+        Ok(radius) if radius > 0 => options.neighbourhood_radius = radius,
+        _ => {
+            eprintln!("{}", USAGE);
+            return 2;
+        }
+    }
+    if tiling {
+        match (
+            option_value(args, "--size").map_or(Ok(DEFAULT_TILE_SIZE), |x| x.parse::<u32>()),
+            option_value(args, "--overlap").map_or(Ok(0), |x| x.parse::<u32>()),
+        ) {
+            (Ok(tile_size), Ok(tile_overlap)) => {
+                options.tile_size = Some(tile_size);
+                options.tile_overlap = tile_overlap;
+            }
+            (_, _) => {
+                eprintln!("{}", USAGE);
+                return 2;
+            }
+        }
+    }
+    let output = option_value(args, "--output")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| import::default_output_path(&dir));
+
+    match import::import_directory(&dir, &options, &output) {
+        Ok(stack_count) => {
+            println!("Wrote {} focus stacks to {}", stack_count, output.display());
+            0
+        }
+        Err(e) => {
+            eprintln!("Import failed: {}", e);
+            1
+        }
+    }
+}
+
+fn focus_maps(args: &[String]) -> i32 {
+    let dataset = match args.first().filter(|x| !x.starts_with("--")) {
+        Some(dataset) => PathBuf::from(dataset),
+        None => {
+            eprintln!("{}", USAGE);
+            return 2;
+        }
+    };
+    let format = match option_value(args, "--format")
+        .map_or(Some(ExportFormat::Png), |x| ExportFormat::from_name(&x))
+    {
+        Some(format) => format,
+        None => {
+            eprintln!("{}", USAGE);
+            return 2;
+        }
+    };
+    let mut options = FocusMapOptions::new(format);
+    options.guided = args.iter().any(|x| x == "--guided");
+    let output = option_value(args, "--output")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| export::default_output_dir(&dataset));
+
+    let dataset_dir = dataset.parent().unwrap_or(Path::new("."));
+    match export::load_stacks(&dataset).and_then(|stacks| {
+        export::export_focus_maps(&stacks, dataset_dir, &output, &options, |_, _| {})
+    }) {
+        Ok(report) => {
+            for (stack_index, reason) in &report.skipped {
+                eprintln!("Skipped focus stack {}: {}", stack_index, reason);
+            }
+            println!(
+                "Wrote {} focus maps to {}",
+                report.written,
+                output.display()
+            );
+            0
+        }
+        Err(e) => {
+            eprintln!("Export failed: {}", e);
+            1
+        }
+    }
+}
+
+// Runs a command line sub command, returns `None` if the GUI should be started instead
+pub fn run(args: &[String]) -> Option<i32> {
+    match args.first().map(|x| x.as_str()) {
+        Some("import") => Some(import(&args[1..], false)),
+        Some("tile") => Some(import(&args[1..], true)),
+        Some("focus-maps") => Some(focus_maps(&args[1..])),
+        Some("--help") | Some("-h") => {
+            println!("{}", USAGE);
+            Some(0)
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,198 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use gtk::glib;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::constants::{
+    AUTOSAVE_INTERVAL_S, CONFIG_DIR_NAME, CONFIG_FILE_NAME, IMAGE_CACHE_SIZE, RECENT_FILES_SIZE,
+    SHORTCUTS,
+};
+
+// Focus level shown when a focus stack is entered, annotated stacks start at their marked level
+// unless the level of the previous stack is kept
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InitialFocus {
+    Keep,
+    First,
+    Middle,
+    Last,
+    Sharpest,
+}
+
+impl InitialFocus {
+    pub const ALL: [InitialFocus; 5] = [
+        InitialFocus::Keep,
+        InitialFocus::First,
+        InitialFocus::Middle,
+        InitialFocus::Last,
+        InitialFocus::Sharpest,
+    ];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            InitialFocus::Keep => "Level of the previous stack",
+            InitialFocus::First => "First level",
+            InitialFocus::Middle => "Middle level",
+            InitialFocus::Last => "Last level",
+            InitialFocus::Sharpest => "Sharpest level",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Theme {
+    System,
+    Light,
+    Dark,
+}
+
+impl Theme {
+    pub const ALL: [Theme; 3] = [Theme::System, Theme::Light, Theme::Dark];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Theme::System => "Follow system",
+            Theme::Light => "Light",
+            Theme::Dark => "Dark",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Preferences {
+    pub skip_marked: bool,
+    pub show_grid: bool,
+    // Number of decoded planes kept in memory
+    pub cache_size: usize,
+    pub initial_focus: InitialFocus,
+    pub theme: Theme,
+    // Seconds between saves of datasets with unsaved changes, 0 disables autosave
+    pub autosave_interval: u32,
+    // Annotations are written to `<name>_<user>.json` instead of the opened dataset
+    pub separate_output: bool,
+    // Folder of the output files, next to the dataset when not set
+    pub output_dir: Option<PathBuf>,
+}
+
+impl Default for Preferences {
+    fn default() -> Self {
+        Preferences {
+            skip_marked: true,
+            show_grid: false,
+            cache_size: IMAGE_CACHE_SIZE,
+            initial_focus: InitialFocus::Keep,
+            theme: Theme::System,
+            autosave_interval: AUTOSAVE_INTERVAL_S,
+            separate_output: false,
+            output_dir: None,
+        }
+    }
+}
+
+// Dataset opened before together with its progress when it was last used
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecentFile {
+    pub path: PathBuf,
+    pub annotated: usize,
+    pub total: usize,
+}
+
+impl RecentFile {
+    // Moves the dataset to the top of the recent files and updates its progress
+    pub fn add_to(recent_files: &mut Vec<RecentFile>, path: &Path, annotated: usize, total: usize) {
+        recent_files.retain(|x| x.path != path);
+        recent_files.insert(
+            0,
+            RecentFile {
+                path: path.to_path_buf(),
+                annotated,
+                total,
+            },
+        );
+        recent_files.truncate(RECENT_FILES_SIZE);
+    }
+}
+
+// User settings stored in the config directory of the user, e.g. ~/.config/focus-annotator/config.json
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Config {
+    // Accelerators by action name, actions that are not listed use their default shortcut and an
+    // empty accelerator disables the shortcut
+    #[serde(default)]
+    pub shortcuts: BTreeMap<String, String>,
+
+    #[serde(default)]
+    pub preferences: Preferences,
+
+    // Most recently used first
+    #[serde(default)]
+    pub recent_files: Vec<RecentFile>,
+
+    #[serde(flatten)]
+    extra: HashMap<String, Value>,
+}
+
+impl Config {
+    pub fn path() -> PathBuf {
+        glib::user_config_dir()
+            .join(CONFIG_DIR_NAME)
+            .join(CONFIG_FILE_NAME)
+    }
+
+    // Loads the config file, a missing or unreadable file results in the default config
+    pub fn load() -> Config {
+        let path = Config::path();
+        if !path.exists() {
+            return Config::default();
+        }
+        match fs::read_to_string(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|contents| serde_json::from_str(&contents).map_err(|e| e.to_string()))
+        {
+            Ok(config) => config,
+            Err(e) => {
+                eprintln!("Could not read config {:?}: {}", path, e);
+                Config::default()
+            }
+        }
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        let path = Config::path();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+        }
+        let contents = serde_json::to_string_pretty(self).map_err(|e| e.to_string())?;
+        fs::write(&path, contents).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn get_shortcut(&self, action: &str) -> String {
+        match self.shortcuts.get(action) {
+            Some(accelerator) => accelerator.clone(),
+            None => Config::default_shortcut(action).to_string(),
+        }
+    }
+
+    // Stores only shortcuts that differ from the default so changed defaults reach existing configs
+    pub fn set_shortcut(&mut self, action: &str, accelerator: &str) {
+        if accelerator == Config::default_shortcut(action) {
+            self.shortcuts.remove(action);
+        } else {
+            self.shortcuts
+                .insert(action.to_string(), accelerator.to_string());
+        }
+    }
+
+    fn default_shortcut(action: &str) -> &'static str {
+        SHORTCUTS
+            .iter()
+            .find(|(name, _, _)| *name == action)
+            .map_or("", |(_, _, accelerator)| accelerator)
+    }
+}
@@ -9,4 +9,123 @@ pub const TOGGLE_NEIGHBOURS_TEXT_TOGGLED: &str = "Hide Neighbours";
 pub const TOGGLE_NEIGHBOURS_TEXT: &str = "Show Neighbours";
 
 pub const SCALE_STEP: f64 = 1.0;
+pub const FOCUS_PAGE_STEP: f64 = 5.0;
+// Digits typed within this interval are read as one z index, e.g. 1 then 2 jumps to 12
+pub const FOCUS_DIGIT_TIMEOUT_MS: u64 = 1000;
+// Scroll distance per focus level, a wheel notch scrolls by one, trackpads in smaller steps
+pub const SCROLL_FOCUS_STEP: f64 = 1.0;
+pub const ZOOM_STEP: f64 = 1.25;
+pub const MAX_ZOOM: f64 = 8.0;
 pub const ANNOTATION_CACHE_FILE_ENDING: &str = "tmp_annotation";
+pub const Z_FILE_NAME_PREFIX: &str = "Z";
+
+pub const DEFAULT_IMPORT_PATTERN: &str =
+    r"I(?P<img>\d+)_X(?P<x>\d+)_Y(?P<y>\d+)_Z(?P<z>\d+)_(?P<py>\d+)_(?P<px>\d+)";
+pub const IMPORT_GROUP_Z: &str = "z";
+pub const IMPORT_GROUP_IMAGE: &str = "img";
+pub const IMPORT_GROUP_PATCH_X: &str = "px";
+pub const IMPORT_GROUP_PATCH_Y: &str = "py";
+pub const IMPORT_IMAGE_EXTENSIONS: [&str; 5] = ["jpg", "jpeg", "png", "tif", "tiff"];
+pub const DEFAULT_TILE_PATTERN: &str = r"I(?P<img>\d+)_X(?P<x>\d+)_Y(?P<y>\d+)_Z(?P<z>\d+)";
+pub const DEFAULT_TILE_SIZE: u32 = 75;
+pub const PATCH_DIR_SUFFIX: &str = "_patches";
+pub const IMAGE_CACHE_SIZE: usize = 256;
+pub const HISTOGRAM_BINS: usize = 1024;
+// Fraction of samples clipped at either end of the histogram by auto levels
+pub const AUTO_LEVELS_CLIP: f32 = 0.005;
+pub const FLICKER_INTERVAL_MS: u64 = 400;
+pub const COMPARE_HOLD_KEY: &str = "space";
+
+pub const THUMBNAIL_SIZE: u32 = 72;
+pub const FILMSTRIP_BEST_CSS_CLASS: &str = "filmstrip-best";
+pub const FILMSTRIP_CURRENT_CSS_CLASS: &str = "filmstrip-current";
+pub const APPLICATION_CSS: &str = "
+.filmstrip-best { outline: 2px solid @accent_color; outline-offset: -2px; }
+.filmstrip-current { background-color: alpha(@accent_bg_color, 0.3); }
+";
+
+// Tiles larger than this are scaled down before they are stitched into a mosaic
+pub const MOSAIC_MAX_TILE_SIZE: u32 = 512;
+pub const OUTLINE_WIDTH: u32 = 2;
+pub const OUTLINE_COLOUR: [f32; 3] = [1.0, 0.8, 0.0];
+pub const NEIGHBOURHOOD_SIZES: [&str; 3] = ["3×3", "5×5", "7×7"];
+
+// Opacity of the heat map drawn over the image by the focus overlays
+pub const OVERLAY_OPACITY: f32 = 0.6;
+// Side length in pixels of the window the local sharpness is averaged over
+pub const SHARPNESS_WINDOW: u32 = 15;
+
+// Drags shorter than this in pixels select a region instead of drawing a new one, drawn regions
+// cover at least this many pixels of the image in both directions
+pub const REGION_MIN_SIZE: f64 = 4.0;
+pub const REGION_COLOUR: [f64; 3] = [1.0, 1.0, 1.0];
+pub const REGION_FOCUS_COLOUR: [f64; 3] = [1.0, 0.8, 0.0];
+
+// Planes are scaled down to at most this size before the drift between them is estimated
+pub const REGISTRATION_MAX_SIZE: u32 = 128;
+// Largest drift between neighbouring planes that is searched, as a fraction of the tile size
+pub const REGISTRATION_MAX_SHIFT: f32 = 0.1;
+
+pub const FOCUS_MAP_DIR_SUFFIX: &str = "_focus_maps";
+pub const FOCUS_MAP_MANIFEST: &str = "manifest.json";
+// Standard deviation in planes of the weight around the annotated plane for guided focus maps
+pub const FOCUS_MAP_GUIDE_SPREAD: f64 = 2.0;
+
+pub const CONFIG_DIR_NAME: &str = "focus-annotator";
+pub const CONFIG_FILE_NAME: &str = "config.json";
+pub const RECENT_FILES_SIZE: usize = 8;
+pub const AUTOSAVE_INTERVAL_S: u32 = 120;
+// Appended to the name of a file while it is being written
+pub const PARTIAL_FILE_SUFFIX: &str = ".part";
+// Appended to the name of the file a session saves to while it has the dataset open
+pub const LOCK_FILE_SUFFIX: &str = ".lock";
+// Between the dataset name and the user name in the name of an output file
+pub const OUTPUT_FILE_SEPARATOR: &str = "_";
+pub const EMPTY_TAB_TITLE: &str = "No Dataset";
+pub const APPLICATION_TITLE: &str = "Focus Annotator";
+// Put in front of the title of datasets with unsaved changes
+pub const DIRTY_INDICATOR: &str = "• ";
+pub const READ_ONLY_INDICATOR: &str = " (read-only)";
+// Window actions that can be bound to a key: action name, description and default accelerator
+pub const SHORTCUTS: [(&str, &str, &str); 32] = [
+    ("increment_focus_scale", "Move up in the focus stack", "W"),
+    ("decrement_focus_scale", "Move down in the focus stack", "S"),
+    ("focus_page_up", "Move up several levels", "Page_Up"),
+    ("focus_page_down", "Move down several levels", "Page_Down"),
+    ("focus_first", "Go to the first level", "Home"),
+    ("focus_last", "Go to the last level", "End"),
+    ("focus_sharpest", "Go to the sharpest level", "X"),
+    ("focus_marked", "Go to the marked level", "J"),
+    ("reset_zoom", "Reset the zoom", "<Control>0"),
+    ("open_in_tab", "Open a dataset in a new tab", "<Control>t"),
+    ("close_tab", "Close the tab", "<Control>w"),
+    ("preferences", "Preferences", "<Control>comma"),
+    ("import_folder", "Import a folder of tiles", "<Control>i"),
+    ("tile_folder", "Tile a folder of full-field images", ""),
+    ("export_focus_maps", "Export focus maps", ""),
+    ("export_all_in_focus", "Export the all in focus image", ""),
+    ("edit_shortcuts", "Keyboard shortcuts", ""),
+    ("save", "Save the dataset", "<Control>s"),
+    (
+        "save_as",
+        "Save the dataset as a new file",
+        "<Control><Shift>s",
+    ),
+    ("mark_focus", "Mark focus and go to the next image", "M"),
+    ("skip_focus", "Skip image", "N"),
+    ("back_focus", "Go back one image", "B"),
+    ("toggle_neighbour", "Show or hide the neighbours", "G"),
+    ("pin_focus", "Pin the current focus level", "P"),
+    ("toggle_split_view", "Side by side view", "V"),
+    (
+        "toggle_flicker",
+        "Flicker between pinned and current level",
+        "F",
+    ),
+    ("toggle_all_in_focus", "All in focus view", "A"),
+    ("toggle_gradient_overlay", "Edge overlay", "E"),
+    ("toggle_sharpness_overlay", "Local sharpness overlay", "H"),
+    ("toggle_difference_overlay", "Difference overlay", "D"),
+    ("mark_region", "Mark focus of the selected region", "R"),
+    ("remove_region", "Remove the selected region", "Delete"),
+];
@@ -0,0 +1,256 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use image::{ImageBuffer, ImageFormat, Luma, Rgb};
+use serde::Serialize;
+
+use crate::constants::{
+    FOCUS_MAP_DIR_SUFFIX, FOCUS_MAP_GUIDE_SPREAD, FOCUS_MAP_MANIFEST, SHARPNESS_WINDOW,
+};
+use crate::imaging::{all_in_focus, focus_indices, Plane};
+use crate::state::{AnnotationZStack, State};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Png,
+    Tiff,
+}
+
+impl ExportFormat {
+    pub fn from_name(name: &str) -> Option<ExportFormat> {
+        match name.to_lowercase().as_str() {
+            "png" => Some(ExportFormat::Png),
+            "tif" | "tiff" => Some(ExportFormat::Tiff),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Png => "png",
+            ExportFormat::Tiff => "tiff",
+        }
+    }
+
+    fn image_format(&self) -> ImageFormat {
+        match self {
+            ExportFormat::Png => ImageFormat::Png,
+            ExportFormat::Tiff => ImageFormat::Tiff,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FocusMapOptions {
+    pub format: ExportFormat,
+    // Weight the sharpness towards the annotated `best_index` of each stack
+    pub guided: bool,
+}
+
+impl FocusMapOptions {
+    pub fn new(format: ExportFormat) -> Self {
+        FocusMapOptions {
+            format,
+            guided: false,
+        }
+    }
+}
+
+// Outcome of a focus map export, the stacks that were skipped are listed with the reason
+#[derive(Debug, Default)]
+pub struct FocusMapReport {
+    pub written: usize,
+    pub skipped: Vec<(usize, String)>,
+}
+
+#[derive(Debug, Serialize)]
+struct ManifestPlane {
+    index: usize,
+    image_path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    page: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    z: Option<f64>,
+}
+
+// Describes one exported map, the pixel values of the map are indices into `planes`
+#[derive(Debug, Serialize)]
+struct ManifestEntry {
+    stack_index: usize,
+    focus_map: String,
+    width: u32,
+    height: u32,
+    best_index: Option<usize>,
+    guided: bool,
+    planes: Vec<ManifestPlane>,
+}
+
+pub fn load_stacks(path: &Path) -> Result<Vec<AnnotationZStack>, String> {
+    let contents = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    serde_json::from_str(&contents).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+// `<dataset>_focus_maps` next to the dataset file
+pub fn default_output_dir(dataset: &Path) -> PathBuf {
+    let stem = dataset
+        .file_stem()
+        .and_then(|x| x.to_str())
+        .unwrap_or("dataset");
+    dataset.with_file_name(format!("{}{}", stem, FOCUS_MAP_DIR_SUFFIX))
+}
+
+pub fn load_planes(stack: &AnnotationZStack, dataset_dir: &Path) -> Result<Vec<Plane>, String> {
+    stack
+        .images
+        .iter()
+        .map(|image| Plane::load(&dataset_dir.join(&image.image_path), image.page))
+        .collect()
+}
+
+// Planes shifted by the drift stored for them, planes without a transform are left as they are
+pub fn load_aligned_planes(
+    stack: &AnnotationZStack,
+    dataset_dir: &Path,
+) -> Result<Vec<Plane>, String> {
+    Ok(load_planes(stack, dataset_dir)?
+        .into_iter()
+        .zip(stack.images.iter())
+        .map(|(plane, image)| match &image.transform {
+            Some(transform) => plane.translated(transform.dx, transform.dy),
+            None => plane,
+        })
+        .collect())
+}
+
+// Writes plane indices as a single channel 16-bit image
+fn write_index_map(
+    indices: &[usize],
+    width: u32,
+    height: u32,
+    path: &Path,
+    format: ExportFormat,
+) -> Result<(), String> {
+    let data = indices
+        .iter()
+        .map(|x| (*x).min(u16::MAX as usize) as u16)
+        .collect();
+    let image: ImageBuffer<Luma<u16>, Vec<u16>> = ImageBuffer::from_raw(width, height, data)
+        .ok_or_else(|| "focus map does not match the image size".to_string())?;
+    image
+        .save_with_format(path, format.image_format())
+        .map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+// Writes a plane with 16 bits per sample
+pub fn write_plane(plane: &Plane, path: &Path, format: ExportFormat) -> Result<(), String> {
+    let data: Vec<u16> = plane
+        .data
+        .iter()
+        .map(|x| (x.clamp(0.0, 1.0) * u16::MAX as f32).round() as u16)
+        .collect();
+    let result = match plane.channels {
+        1 => ImageBuffer::<Luma<u16>, Vec<u16>>::from_raw(plane.width, plane.height, data)
+            .map(|image| image.save_with_format(path, format.image_format())),
+        _ => ImageBuffer::<Rgb<u16>, Vec<u16>>::from_raw(plane.width, plane.height, data)
+            .map(|image| image.save_with_format(path, format.image_format())),
+    };
+    match result {
+        Some(result) => result.map_err(|e| format!("{}: {}", path.display(), e)),
+        None => Err("image data does not match the image size".to_string()),
+    }
+}
+
+// Fuses all planes of a stack into one sharp image and writes it to `path`, the format follows
+// the file extension and defaults to PNG
+// The drift of the planes is corrected when `aligned` is set, like in the all in focus view
+pub fn export_all_in_focus(
+    stack: &AnnotationZStack,
+    dataset_dir: &Path,
+    path: &Path,
+    aligned: bool,
+) -> Result<(), String> {
+    let planes = match aligned {
+        true => load_aligned_planes(stack, dataset_dir)?,
+        false => load_planes(stack, dataset_dir)?,
+    };
+    let composite = all_in_focus(&planes, SHARPNESS_WINDOW)
+        .ok_or_else(|| "the planes of the focus stack differ in size".to_string())?;
+    let format = path
+        .extension()
+        .and_then(|x| x.to_str())
+        .and_then(ExportFormat::from_name)
+        .unwrap_or(ExportFormat::Png);
+    write_plane(&composite, path, format)
+}
+
+// Computes a depth from focus map for every stack and writes them with a manifest into
+// `output_dir`. Stacks whose planes can not be loaded are skipped, `progress` is called with the
+// number of stacks done and the number of all stacks after each stack.
+pub fn export_focus_maps<F: FnMut(usize, usize)>(
+    stacks: &[AnnotationZStack],
+    dataset_dir: &Path,
+    output_dir: &Path,
+    options: &FocusMapOptions,
+    mut progress: F,
+) -> Result<FocusMapReport, String> {
+    fs::create_dir_all(output_dir).map_err(|e| format!("{}: {}", output_dir.display(), e))?;
+
+    let mut manifest = Vec::new();
+    let mut skipped = Vec::new();
+    for (stack_index, stack) in stacks.iter().enumerate() {
+        progress(stack_index, stacks.len());
+        let planes = match load_aligned_planes(stack, dataset_dir) {
+            Ok(planes) => planes,
+            Err(e) => {
+                skipped.push((stack_index, e));
+                continue;
+            }
+        };
+        let guide = match options.guided {
+            true => stack.best_index.map(|x| (x, FOCUS_MAP_GUIDE_SPREAD)),
+            false => None,
+        };
+        let indices = match focus_indices(&planes, SHARPNESS_WINDOW, guide) {
+            Some(indices) => indices,
+            None => {
+                skipped.push((stack_index, "planes differ in size".to_string()));
+                continue;
+            }
+        };
+
+        let (width, height) = (planes[0].width, planes[0].height);
+        let file_name = format!("stack_{:05}.{}", stack_index, options.format.extension());
+        write_index_map(
+            &indices,
+            width,
+            height,
+            &output_dir.join(&file_name),
+            options.format,
+        )?;
+
+        manifest.push(ManifestEntry {
+            stack_index,
+            focus_map: file_name,
+            width,
+            height,
+            best_index: stack.best_index,
+            guided: guide.is_some(),
+            planes: stack
+                .images
+                .iter()
+                .enumerate()
+                .map(|(index, image)| ManifestPlane {
+                    index,
+                    image_path: image.image_path.clone(),
+                    page: image.page,
+                    z: image.get_z(),
+                })
+                .collect(),
+        });
+    }
+
+    progress(stacks.len(), stacks.len());
+    let written = manifest.len();
+    State::save_file(output_dir.join(FOCUS_MAP_MANIFEST), &manifest)?;
+    Ok(FocusMapReport { written, skipped })
+}
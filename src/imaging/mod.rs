@@ -0,0 +1,785 @@
+use std::borrow::Borrow;
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use tiff::decoder::{Decoder, DecodingResult};
+use tiff::ColorType;
+
+use crate::constants::{
+    AUTO_LEVELS_CLIP, HISTOGRAM_BINS, MOSAIC_MAX_TILE_SIZE, OUTLINE_COLOUR, OUTLINE_WIDTH,
+    OVERLAY_OPACITY, REGISTRATION_MAX_SHIFT, REGISTRATION_MAX_SIZE, SHARPNESS_WINDOW,
+};
+
+// Decoded image with samples normalised to 0.0..=1.0, interleaved if it has more than one channel
+#[derive(Debug, Clone)]
+pub struct Plane {
+    pub width: u32,
+    pub height: u32,
+    pub channels: usize,
+    pub data: Vec<f32>,
+}
+
+impl Plane {
+    pub fn new(width: u32, height: u32, channels: usize) -> Self {
+        Plane {
+            width,
+            height,
+            channels,
+            data: vec![0.0; width as usize * height as usize * channels],
+        }
+    }
+
+    pub fn load(path: &Path, page: Option<usize>) -> Result<Plane, String> {
+        match page {
+            Some(page) => Plane::load_tiff_page(path, page),
+            None => Plane::load_image(path),
+        }
+        .map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    fn load_image(path: &Path) -> Result<Plane, String> {
+        let image = image::open(path).map_err(|e| e.to_string())?;
+        if image.color().has_color() {
+            let image = image.into_rgb32f();
+            Ok(Plane {
+                width: image.width(),
+                height: image.height(),
+                channels: 3,
+                data: image.into_raw(),
+            })
+        } else {
+            let image = image.to_luma32f();
+            Ok(Plane {
+                width: image.width(),
+                height: image.height(),
+                channels: 1,
+                data: image.into_raw(),
+            })
+        }
+    }
+
+    fn load_tiff_page(path: &Path, page: usize) -> Result<Plane, String> {
+        let file = fs::File::open(path).map_err(|e| e.to_string())?;
+        let mut decoder = Decoder::new(BufReader::new(file)).map_err(|e| e.to_string())?;
+        decoder.seek_to_image(page).map_err(|e| e.to_string())?;
+
+        let (width, height) = decoder.dimensions().map_err(|e| e.to_string())?;
+        let source_channels = match decoder.colortype().map_err(|e| e.to_string())? {
+            ColorType::Gray(_) => 1,
+            ColorType::GrayA(_) => 2,
+            ColorType::RGB(_) => 3,
+            ColorType::RGBA(_) => 4,
+            color_type => return Err(format!("unsupported colour type {:?}", color_type)),
+        };
+
+        let samples: Vec<f32> = match decoder.read_image().map_err(|e| e.to_string())? {
+            DecodingResult::U8(data) => data.iter().map(|x| *x as f32 / u8::MAX as f32).collect(),
+            DecodingResult::U16(data) => data.iter().map(|x| *x as f32 / u16::MAX as f32).collect(),
+            DecodingResult::F32(data) => {
+                // Float data has no fixed range so it is stretched to its minimum and maximum
+                let min = data.iter().cloned().fold(f32::INFINITY, f32::min);
+                let max = data.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+                let range = if max > min { max - min } else { 1.0 };
+                data.iter().map(|x| (x - min) / range).collect()
+            }
+            _ => return Err("unsupported sample format".to_string()),
+        };
+
+        // Alpha is dropped, the annotation view is always opaque
+        let channels = if source_channels >= 3 { 3 } else { 1 };
+        let data = samples
+            .chunks(source_channels)
+            .flat_map(|pixel| pixel[..channels].to_vec())
+            .collect();
+
+        Ok(Plane {
+            width,
+            height,
+            channels,
+            data,
+        })
+    }
+
+    fn select_channel(&self, channel: Channel) -> Plane {
+        let offset = match (self.channels, channel) {
+            (1, _) | (_, Channel::All) => return self.clone(),
+            (_, Channel::Red) => 0,
+            (_, Channel::Green) => 1,
+            (_, Channel::Blue) => 2,
+        };
+        Plane {
+            width: self.width,
+            height: self.height,
+            channels: 1,
+            data: self
+                .data
+                .iter()
+                .skip(offset)
+                .step_by(self.channels)
+                .cloned()
+                .collect(),
+        }
+    }
+
+    // Sample values below and above which the given fraction of samples lies
+    pub fn percentiles(&self, fraction: f32) -> (f32, f32) {
+        let mut histogram = [0usize; HISTOGRAM_BINS];
+        for x in self.data.iter() {
+            let bin = (x.clamp(0.0, 1.0) * (HISTOGRAM_BINS - 1) as f32) as usize;
+            histogram[bin] += 1;
+        }
+
+        let threshold = (self.data.len() as f32 * fraction) as usize;
+        let low = Plane::histogram_bin_above(histogram.iter().enumerate(), threshold);
+        let high = Plane::histogram_bin_above(histogram.iter().enumerate().rev(), threshold);
+
+        let to_value = |bin: usize| bin as f32 / (HISTOGRAM_BINS - 1) as f32;
+        (to_value(low), to_value(high))
+    }
+
+    fn histogram_bin_above<'a>(
+        mut bins: impl Iterator<Item = (usize, &'a usize)>,
+        threshold: usize,
+    ) -> usize {
+        let mut count = 0;
+        bins.find(|(_, x)| {
+            count += **x;
+            count > threshold
+        })
+        .map_or(0, |(bin, _)| bin)
+    }
+
+    pub fn adjusted(&self, settings: &DisplaySettings) -> Plane {
+        if settings.is_identity() {
+            return self.clone();
+        }
+
+        let mut plane = self.select_channel(settings.channel);
+        let (black, white) = if settings.auto_levels {
+            plane.percentiles(AUTO_LEVELS_CLIP)
+        } else {
+            (0.0, 1.0)
+        };
+        let range = if white > black { white - black } else { 1.0 };
+        let exponent = 1.0 / settings.gamma.max(f32::EPSILON);
+
+        plane.data.iter_mut().for_each(|x| {
+            let value = (*x - black) / range;
+            let value = (value - 0.5) * settings.contrast + 0.5 + settings.brightness;
+            *x = value.clamp(0.0, 1.0).powf(exponent);
+        });
+        plane
+    }
+
+    pub fn luminance(&self) -> Vec<f32> {
+        match self.channels {
+            1 => self.data.clone(),
+            _ => self
+                .data
+                .chunks(self.channels)
+                .map(|x| 0.2126 * x[0] + 0.7152 * x[1] + 0.0722 * x[2])
+                .collect(),
+        }
+    }
+
+    // Variance of the Laplacian, higher values mean a sharper image
+    pub fn sharpness(&self) -> f64 {
+        let width = self.width as usize;
+        let height = self.height as usize;
+        if width < 3 || height < 3 {
+            return 0.0;
+        }
+
+        let luminance = self.luminance();
+        let laplacian: Vec<f64> = (1..height - 1)
+            .flat_map(|y| (1..width - 1).map(move |x| (x, y)))
+            .map(|(x, y)| {
+                let at = |x: usize, y: usize| luminance[y * width + x] as f64;
+                at(x - 1, y) + at(x + 1, y) + at(x, y - 1) + at(x, y + 1) - 4.0 * at(x, y)
+            })
+            .collect();
+
+        let mean = laplacian.iter().sum::<f64>() / laplacian.len() as f64;
+        laplacian.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / laplacian.len() as f64
+    }
+
+    // Sobel gradient magnitude of the luminance, strong edges only appear in focus
+    pub fn gradient_magnitude(&self) -> Vec<f32> {
+        let width = self.width as usize;
+        let height = self.height as usize;
+        let luminance = self.luminance();
+        let mut magnitude = vec![0.0; width * height];
+        if width < 3 || height < 3 {
+            return magnitude;
+        }
+
+        for y in 1..height - 1 {
+            for x in 1..width - 1 {
+                let at = |x: usize, y: usize| luminance[y * width + x];
+                let gx = at(x + 1, y - 1) + 2.0 * at(x + 1, y) + at(x + 1, y + 1)
+                    - at(x - 1, y - 1)
+                    - 2.0 * at(x - 1, y)
+                    - at(x - 1, y + 1);
+                let gy = at(x - 1, y + 1) + 2.0 * at(x, y + 1) + at(x + 1, y + 1)
+                    - at(x - 1, y - 1)
+                    - 2.0 * at(x, y - 1)
+                    - at(x + 1, y - 1);
+                magnitude[y * width + x] = (gx * gx + gy * gy).sqrt();
+            }
+        }
+        magnitude
+    }
+
+    // Squared Laplacian averaged over a `window` x `window` neighbourhood of each pixel
+    pub fn local_sharpness(&self, window: u32) -> Vec<f32> {
+        let width = self.width as usize;
+        let height = self.height as usize;
+        let luminance = self.luminance();
+        if width < 3 || height < 3 {
+            return vec![0.0; width * height];
+        }
+
+        // Summed area table of the squared Laplacian with an extra leading row and column of zeros
+        let mut table = vec![0.0f64; (width + 1) * (height + 1)];
+        for y in 0..height {
+            let mut row_sum = 0.0;
+            for x in 0..width {
+                let laplacian = if x == 0 || y == 0 || x == width - 1 || y == height - 1 {
+                    0.0
+                } else {
+                    let at = |x: usize, y: usize| luminance[y * width + x] as f64;
+                    at(x - 1, y) + at(x + 1, y) + at(x, y - 1) + at(x, y + 1) - 4.0 * at(x, y)
+                };
+                row_sum += laplacian * laplacian;
+                table[(y + 1) * (width + 1) + x + 1] = table[y * (width + 1) + x + 1] + row_sum;
+            }
+        }
+
+        let half = (window / 2) as usize;
+        (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .map(|(x, y)| {
+                let (x0, x1) = (x.saturating_sub(half), (x + half + 1).min(width));
+                let (y0, y1) = (y.saturating_sub(half), (y + half + 1).min(height));
+                let at = |x: usize, y: usize| table[y * (width + 1) + x];
+                let sum = at(x1, y1) - at(x0, y1) - at(x1, y0) + at(x0, y0);
+                (sum / ((x1 - x0) * (y1 - y0)) as f64) as f32
+            })
+            .collect()
+    }
+
+    // Absolute luminance difference to another plane of the same size
+    pub fn difference(&self, other: &Plane) -> Option<Vec<f32>> {
+        if (self.width, self.height) != (other.width, other.height) {
+            return None;
+        }
+        Some(
+            self.luminance()
+                .iter()
+                .zip(other.luminance().iter())
+                .map(|(a, b)| (a - b).abs())
+                .collect(),
+        )
+    }
+
+    // Greyscale copy of the plane with the values drawn over it as a heat map,
+    // values are stretched so the largest one is shown in white
+    pub fn with_heat_map(&self, values: &[f32]) -> Plane {
+        let max = values.iter().cloned().fold(0.0, f32::max);
+        let scale = if max > 0.0 { 1.0 / max } else { 0.0 };
+
+        let data = self
+            .luminance()
+            .iter()
+            .zip(values.iter())
+            .flat_map(|(grey, value)| {
+                let value = value * scale;
+                let heat = [
+                    (value * 3.0).clamp(0.0, 1.0),
+                    (value * 3.0 - 1.0).clamp(0.0, 1.0),
+                    (value * 3.0 - 2.0).clamp(0.0, 1.0),
+                ];
+                heat.map(|x| grey * (1.0 - OVERLAY_OPACITY) + x * OVERLAY_OPACITY)
+            })
+            .collect();
+        Plane {
+            width: self.width,
+            height: self.height,
+            channels: 3,
+            data,
+        }
+    }
+
+    // Applies a focus overlay, `previous` is the plane below in the stack used for the difference
+    pub fn overlaid(&self, overlay: Overlay, previous: Option<&Plane>) -> Plane {
+        let values = match overlay {
+            Overlay::None => None,
+            Overlay::Gradient => Some(self.gradient_magnitude()),
+            Overlay::Sharpness => Some(self.local_sharpness(SHARPNESS_WINDOW)),
+            Overlay::Difference => previous.and_then(|previous| self.difference(previous)),
+        };
+        match values {
+            Some(values) => self.with_heat_map(&values),
+            None => self.clone(),
+        }
+    }
+
+    // Square crop of the centre scaled down to `size` pixels by averaging
+    pub fn thumbnail(&self, size: u32) -> Plane {
+        let crop = self.width.min(self.height);
+        if crop == 0 || size == 0 {
+            return Plane::new(0, 0, self.channels);
+        }
+        let size = size.min(crop);
+        let offset_x = (self.width - crop) / 2;
+        let offset_y = (self.height - crop) / 2;
+
+        let mut thumbnail = Plane::new(size, size, self.channels);
+        for y in 0..size {
+            for x in 0..size {
+                let source_x = (offset_x + x * crop / size)..(offset_x + (x + 1) * crop / size);
+                let source_y = (offset_y + y * crop / size)..(offset_y + (y + 1) * crop / size);
+                let count = (source_x.len() * source_y.len()) as f32;
+                for channel in 0..self.channels {
+                    let sum: f32 = source_y
+                        .clone()
+                        .flat_map(|sy| source_x.clone().map(move |sx| (sx, sy)))
+                        .map(|(sx, sy)| self.data[self.index(sx, sy) + channel])
+                        .sum();
+                    let index = thumbnail.index(x, y) + channel;
+                    thumbnail.data[index] = sum / count;
+                }
+            }
+        }
+        thumbnail
+    }
+
+    // Averages blocks of `factor` x `factor` pixels
+    pub fn downscaled(&self, factor: u32) -> Plane {
+        if factor <= 1 {
+            return self.clone();
+        }
+
+        let mut plane = Plane::new(self.width / factor, self.height / factor, self.channels);
+        let count = (factor * factor) as f32;
+        for y in 0..plane.height {
+            for x in 0..plane.width {
+                let target = plane.index(x, y);
+                for sy in y * factor..(y + 1) * factor {
+                    for sx in x * factor..(x + 1) * factor {
+                        let source = self.index(sx, sy);
+                        for channel in 0..self.channels {
+                            plane.data[target + channel] += self.data[source + channel] / count;
+                        }
+                    }
+                }
+            }
+        }
+        plane
+    }
+
+    // Shifts the content by whole pixels, uncovered pixels are black
+    pub fn translated(&self, dx: f64, dy: f64) -> Plane {
+        let (dx, dy) = (dx.round() as i64, dy.round() as i64);
+        if dx == 0 && dy == 0 {
+            return self.clone();
+        }
+
+        let mut plane = Plane::new(self.width, self.height, self.channels);
+        for y in 0..self.height as i64 {
+            let source_y = y - dy;
+            if source_y < 0 || source_y >= self.height as i64 {
+                continue;
+            }
+            for x in 0..self.width as i64 {
+                let source_x = x - dx;
+                if source_x < 0 || source_x >= self.width as i64 {
+                    continue;
+                }
+                let source = self.index(source_x as u32, source_y as u32);
+                let target = plane.index(x as u32, y as u32);
+                plane.data[target..target + self.channels]
+                    .copy_from_slice(&self.data[source..source + self.channels]);
+            }
+        }
+        plane
+    }
+
+    // Draws a rectangle outline, the plane needs three channels
+    pub fn draw_rectangle(&mut self, x: u32, y: u32, width: u32, height: u32, colour: [f32; 3]) {
+        let x_end = (x + width).min(self.width);
+        let y_end = (y + height).min(self.height);
+        for py in y..y_end {
+            for px in x..x_end {
+                let on_border = px < x + OUTLINE_WIDTH
+                    || px + OUTLINE_WIDTH >= x_end
+                    || py < y + OUTLINE_WIDTH
+                    || py + OUTLINE_WIDTH >= y_end;
+                if on_border {
+                    let index = self.index(px, py);
+                    self.data[index..index + 3].copy_from_slice(&colour);
+                }
+            }
+        }
+    }
+
+    pub fn index(&self, x: u32, y: u32) -> usize {
+        (y as usize * self.width as usize + x as usize) * self.channels
+    }
+
+    pub fn to_rgb8(&self) -> Vec<u8> {
+        let to_byte = |x: f32| (x.clamp(0.0, 1.0) * 255.0).round() as u8;
+        match self.channels {
+            1 => self
+                .data
+                .iter()
+                .flat_map(|x| {
+                    let value = to_byte(*x);
+                    [value, value, value]
+                })
+                .collect(),
+            _ => self.data.iter().map(|x| to_byte(*x)).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    All,
+    Red,
+    Green,
+    Blue,
+}
+
+impl Channel {
+    pub const ALL: [Channel; 4] = [Channel::All, Channel::Red, Channel::Green, Channel::Blue];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Channel::All => "All channels",
+            Channel::Red => "Red",
+            Channel::Green => "Green",
+            Channel::Blue => "Blue",
+        }
+    }
+}
+
+// Visualisations drawn over the displayed image to make focus differences visible
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overlay {
+    None,
+    Gradient,
+    Sharpness,
+    Difference,
+}
+
+impl Overlay {
+    pub const ALL: [Overlay; 4] = [
+        Overlay::None,
+        Overlay::Gradient,
+        Overlay::Sharpness,
+        Overlay::Difference,
+    ];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Overlay::None => "No overlay",
+            Overlay::Gradient => "Edges (gradient magnitude)",
+            Overlay::Sharpness => "Local sharpness",
+            Overlay::Difference => "Difference to previous plane",
+        }
+    }
+}
+
+// Adjustments applied to the displayed images only, the files are never modified
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DisplaySettings {
+    pub brightness: f32,
+    pub contrast: f32,
+    pub gamma: f32,
+    pub auto_levels: bool,
+    pub channel: Channel,
+}
+
+impl Default for DisplaySettings {
+    fn default() -> Self {
+        DisplaySettings {
+            brightness: 0.0,
+            contrast: 1.0,
+            gamma: 1.0,
+            auto_levels: false,
+            channel: Channel::All,
+        }
+    }
+}
+
+impl DisplaySettings {
+    pub fn is_identity(&self) -> bool {
+        *self == DisplaySettings::default()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NeighbourSettings {
+    pub mosaic: bool,
+    pub outline_centre: bool,
+    // 1 for a 3x3 neighbourhood, 2 for 5x5, ...
+    pub radius: usize,
+    // Show annotated neighbours at their own marked plane instead of the current one
+    pub own_focus: bool,
+}
+
+impl Default for NeighbourSettings {
+    fn default() -> Self {
+        NeighbourSettings {
+            mosaic: false,
+            outline_centre: true,
+            radius: 1,
+            own_focus: false,
+        }
+    }
+}
+
+// Stitches a square, row-major grid of tiles into one image, neighbouring tiles share `overlap` pixels
+pub fn mosaic(tiles: &[Option<Rc<Plane>>], overlap: u32, outline_centre: bool) -> Option<Plane> {
+    let size = (tiles.len() as f64).sqrt() as usize;
+    let centre = tiles.get(tiles.len() / 2)?.as_ref()?;
+
+    let factor =
+        (centre.width.max(centre.height) + MOSAIC_MAX_TILE_SIZE - 1) / MOSAIC_MAX_TILE_SIZE;
+    let tile_width = centre.width / factor;
+    let tile_height = centre.height / factor;
+    let overlap = (overlap / factor).min(tile_width.min(tile_height).saturating_sub(1));
+    let stride_x = tile_width - overlap;
+    let stride_y = tile_height - overlap;
+
+    let channels = if outline_centre {
+        3
+    } else {
+        tiles
+            .iter()
+            .flatten()
+            .map(|x| x.channels)
+            .max()
+            .unwrap_or(1)
+    };
+    let mut mosaic = Plane::new(
+        stride_x * (size as u32 - 1) + tile_width,
+        stride_y * (size as u32 - 1) + tile_height,
+        channels,
+    );
+
+    for (index, tile) in tiles.iter().enumerate() {
+        let tile = match tile {
+            Some(tile) => tile.downscaled(factor),
+            None => continue,
+        };
+        let offset_x = (index % size) as u32 * stride_x;
+        let offset_y = (index / size) as u32 * stride_y;
+        for y in 0..tile.height.min(mosaic.height - offset_y) {
+            for x in 0..tile.width.min(mosaic.width - offset_x) {
+                let source = tile.index(x, y);
+                let target = mosaic.index(offset_x + x, offset_y + y);
+                for channel in 0..channels {
+                    mosaic.data[target + channel] =
+                        tile.data[source + channel.min(tile.channels - 1)];
+                }
+            }
+        }
+    }
+
+    if outline_centre {
+        let centre_index = (size / 2) as u32;
+        mosaic.draw_rectangle(
+            centre_index * stride_x,
+            centre_index * stride_y,
+            tile_width,
+            tile_height,
+            OUTLINE_COLOUR,
+        );
+    }
+    Some(mosaic)
+}
+
+// Index of the plane with the highest local sharpness for every pixel of a stack. With `guide`
+// (plane index, spread) the sharpness is weighted by a Gaussian around that plane
+pub fn focus_indices(
+    planes: &[Plane],
+    window: u32,
+    guide: Option<(usize, f64)>,
+) -> Option<Vec<usize>> {
+    let first = planes.first()?;
+    if planes
+        .iter()
+        .any(|x| (x.width, x.height) != (first.width, first.height))
+    {
+        return None;
+    }
+
+    let mut best = vec![(0, f32::NEG_INFINITY); first.width as usize * first.height as usize];
+    for (index, plane) in planes.iter().enumerate() {
+        let weight = guide.map_or(1.0, |(guide_index, spread)| {
+            let distance = index as f64 - guide_index as f64;
+            (-distance * distance / (2.0 * spread * spread)).exp() as f32
+        });
+        for (pixel, sharpness) in plane.local_sharpness(window).iter().enumerate() {
+            let score = sharpness * weight;
+            if score > best[pixel].1 {
+                best[pixel] = (index, score);
+            }
+        }
+    }
+    Some(best.into_iter().map(|(index, _)| index).collect())
+}
+
+// Shift (dx, dy) that moves `moving` onto `reference`. The gradient magnitude of downscaled copies
+// is compared for every whole pixel shift up to a fraction of the size, edges match between planes
+// even if their focus differs.
+pub fn estimate_translation(reference: &Plane, moving: &Plane) -> Option<(f64, f64)> {
+    if (reference.width, reference.height) != (moving.width, moving.height) {
+        return None;
+    }
+    let factor =
+        (reference.width.max(reference.height) + REGISTRATION_MAX_SIZE - 1) / REGISTRATION_MAX_SIZE;
+    let reference = reference.downscaled(factor);
+    let moving = moving.downscaled(factor);
+    let (width, height) = (reference.width as i64, reference.height as i64);
+    if width < 3 || height < 3 {
+        return None;
+    }
+
+    let centred = |values: Vec<f32>| {
+        let mean = values.iter().sum::<f32>() / values.len() as f32;
+        values.into_iter().map(|x| x - mean).collect::<Vec<f32>>()
+    };
+    let a = centred(reference.gradient_magnitude());
+    let b = centred(moving.gradient_magnitude());
+
+    let max_shift = ((width.min(height) as f32 * REGISTRATION_MAX_SHIFT).round() as i64).max(1);
+    let mut best = (0, 0, f32::NEG_INFINITY);
+    for shift_y in -max_shift..=max_shift {
+        for shift_x in -max_shift..=max_shift {
+            // Normalised cross correlation of the overlapping part
+            let (mut ab, mut aa, mut bb) = (0.0, 0.0, 0.0);
+            for y in 0.max(shift_y)..height.min(height + shift_y) {
+                for x in 0.max(shift_x)..width.min(width + shift_x) {
+                    let value_a = a[(y * width + x) as usize];
+                    let value_b = b[((y - shift_y) * width + x - shift_x) as usize];
+                    ab += value_a * value_b;
+                    aa += value_a * value_a;
+                    bb += value_b * value_b;
+                }
+            }
+            let score = if aa > 0.0 && bb > 0.0 {
+                ab / (aa * bb).sqrt()
+            } else {
+                0.0
+            };
+            if score > best.2 {
+                best = (shift_x, shift_y, score);
+            }
+        }
+    }
+    Some((
+        (best.0 * factor as i64) as f64,
+        (best.1 * factor as i64) as f64,
+    ))
+}
+
+// Shift of every plane onto the plane at `reference_index`. Neighbouring planes are registered to
+// each other as they are most similar, their shifts add up towards the reference.
+pub fn register_planes<P: Borrow<Plane>>(
+    planes: &[P],
+    reference_index: usize,
+) -> Option<Vec<(f64, f64)>> {
+    if reference_index >= planes.len() {
+        return None;
+    }
+    let mut shifts = vec![(0.0, 0.0); planes.len()];
+    for index in reference_index + 1..planes.len() {
+        let (dx, dy) = estimate_translation(planes[index - 1].borrow(), planes[index].borrow())?;
+        shifts[index] = (shifts[index - 1].0 + dx, shifts[index - 1].1 + dy);
+    }
+    for index in (0..reference_index).rev() {
+        let (dx, dy) = estimate_translation(planes[index + 1].borrow(), planes[index].borrow())?;
+        shifts[index] = (shifts[index + 1].0 + dx, shifts[index + 1].1 + dy);
+    }
+    Some(shifts)
+}
+
+// Extended depth of field composite, every pixel is taken from the plane that is sharpest there
+pub fn all_in_focus(planes: &[Plane], window: u32) -> Option<Plane> {
+    let indices = focus_indices(planes, window, None)?;
+    let first = planes.first()?;
+    let channels = planes.iter().map(|x| x.channels).max()?;
+
+    let mut composite = Plane::new(first.width, first.height, channels);
+    for (pixel, index) in indices.iter().enumerate() {
+        let plane = &planes[*index];
+        for channel in 0..channels {
+            composite.data[pixel * channels + channel] =
+                plane.data[pixel * plane.channels + channel.min(plane.channels - 1)];
+        }
+    }
+    Some(composite)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct PlaneKey {
+    path: PathBuf,
+    page: Option<usize>,
+}
+
+// Keeps the most recently used decoded planes so scrolling through a stack does not decode again
+#[derive(Debug)]
+pub struct PlaneCache {
+    capacity: usize,
+    planes: HashMap<PlaneKey, Rc<Plane>>,
+    usage: VecDeque<PlaneKey>,
+}
+
+impl PlaneCache {
+    pub fn new(capacity: usize) -> Self {
+        PlaneCache {
+            capacity,
+            planes: HashMap::new(),
+            usage: VecDeque::new(),
+        }
+    }
+
+    pub fn get(&mut self, path: &Path, page: Option<usize>) -> Result<Rc<Plane>, String> {
+        let key = PlaneKey {
+            path: path.to_path_buf(),
+            page,
+        };
+
+        if let Some(plane) = self.planes.get(&key) {
+            let plane = plane.clone();
+            self.usage.retain(|x| x != &key);
+            self.usage.push_back(key);
+            return Ok(plane);
+        }
+
+        let plane = Rc::new(Plane::load(path, page)?);
+        self.planes.insert(key.clone(), plane.clone());
+        self.usage.push_back(key);
+        self.shrink();
+        Ok(plane)
+    }
+
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.shrink();
+    }
+
+    fn shrink(&mut self) {
+        while self.usage.len() > self.capacity {
+            if let Some(key) = self.usage.pop_front() {
+                self.planes.remove(&key);
+            }
+        }
+    }
+}
@@ -0,0 +1,388 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use regex::Regex;
+
+use crate::constants::{
+    FOCUS_MAP_DIR_SUFFIX, IMPORT_GROUP_IMAGE, IMPORT_GROUP_PATCH_X, IMPORT_GROUP_PATCH_Y,
+    IMPORT_GROUP_Z, IMPORT_IMAGE_EXTENSIONS, PATCH_DIR_SUFFIX,
+};
+use crate::state::{AnnotationImage, AnnotationZStack, Neighbours, State};
+
+pub mod tiling;
+
+#[derive(Debug, Clone)]
+pub struct ImportOptions {
+    pub pattern: String,
+    // Cut full-field images into patches of this size instead of importing them as tiles
+    pub tile_size: Option<u32>,
+    pub tile_overlap: u32,
+    // Neighbours stored per image, 1 for 3x3, 2 for 5x5, ...
+    pub neighbourhood_radius: usize,
+}
+
+impl ImportOptions {
+    pub fn new(pattern: String) -> Self {
+        ImportOptions {
+            pattern,
+            tile_size: None,
+            tile_overlap: 0,
+            neighbourhood_radius: 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Tile {
+    path: String,
+    field: Vec<String>,
+    patch: (i64, i64),
+    z: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct ImportPattern {
+    regex: Regex,
+    field_groups: Vec<String>,
+}
+
+impl ImportPattern {
+    pub fn new(pattern: &str) -> Result<Self, String> {
+        let regex = Regex::new(pattern).map_err(|e| e.to_string())?;
+        if !regex
+            .capture_names()
+            .any(|name| name == Some(IMPORT_GROUP_Z))
+        {
+            return Err(format!(
+                "pattern needs a named group `{}` for the z level",
+                IMPORT_GROUP_Z
+            ));
+        }
+
+        // All remaining named groups identify the field of view a tile belongs to
+        let field_groups = regex
+            .capture_names()
+            .flatten()
+            .filter(|name| {
+                ![
+                    IMPORT_GROUP_Z,
+                    IMPORT_GROUP_IMAGE,
+                    IMPORT_GROUP_PATCH_X,
+                    IMPORT_GROUP_PATCH_Y,
+                ]
+                .contains(name)
+            })
+            .map(|name| name.to_string())
+            .collect();
+
+        Ok(ImportPattern {
+            regex,
+            field_groups,
+        })
+    }
+
+    fn parse(&self, file_name: &str, path: String) -> Option<Tile> {
+        let captures = self.regex.captures(file_name)?;
+        let z = captures.name(IMPORT_GROUP_Z)?.as_str().to_string();
+        let field = self
+            .field_groups
+            .iter()
+            .map(|name| {
+                captures
+                    .name(name)
+                    .map_or_else(String::new, |x| x.as_str().to_string())
+            })
+            .collect();
+        let patch_coordinate = |name| {
+            captures
+                .name(name)
+                .and_then(|x| x.as_str().parse::<i64>().ok())
+                .unwrap_or(0)
+        };
+
+        Some(Tile {
+            path,
+            field,
+            patch: (
+                patch_coordinate(IMPORT_GROUP_PATCH_X),
+                patch_coordinate(IMPORT_GROUP_PATCH_Y),
+            ),
+            z,
+        })
+    }
+}
+
+// Smallest distance between two distinct patch coordinates, i.e. the tile spacing
+fn patch_step(values: &mut Vec<i64>) -> i64 {
+    values.sort();
+    values.dedup();
+    values
+        .windows(2)
+        .map(|pair| pair[1] - pair[0])
+        .min()
+        .unwrap_or(1)
+}
+
+// Patches and focus maps written by an earlier run are skipped, their names still match the
+// pattern of the images they were made from
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<(), String> {
+    let entries = fs::read_dir(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+    for entry in entries {
+        let path = entry.map_err(|e| e.to_string())?.path();
+        if path.is_dir() {
+            let generated = path
+                .file_name()
+                .and_then(|x| x.to_str())
+                .map_or(false, |x| {
+                    x.ends_with(PATCH_DIR_SUFFIX) || x.ends_with(FOCUS_MAP_DIR_SUFFIX)
+                });
+            if !generated {
+                collect_files(&path, files)?;
+            }
+        } else if path
+            .extension()
+            .and_then(|x| x.to_str())
+            .map_or(false, |x| {
+                IMPORT_IMAGE_EXTENSIONS.contains(&x.to_lowercase().as_str())
+            })
+        {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+// Paths are stored relative to the dataset file, files outside of its folder are kept absolute
+pub fn relative_path(path: &Path, dataset_dir: &Path) -> String {
+    path.strip_prefix(dataset_dir)
+        .unwrap_or(path)
+        .to_string_lossy()
+        .replace('\\', "/")
+}
+
+fn build_stacks(tiles: Vec<Tile>, radius: usize) -> Vec<AnnotationZStack> {
+    let mut patch_values: HashMap<Vec<String>, (Vec<i64>, Vec<i64>)> = HashMap::new();
+    let mut lookup: HashMap<(Vec<String>, (i64, i64), String), String> = HashMap::new();
+    let mut stacks: BTreeMap<(Vec<String>, (i64, i64)), Vec<Tile>> = BTreeMap::new();
+    for tile in tiles {
+        let values = patch_values.entry(tile.field.clone()).or_default();
+        values.0.push(tile.patch.0);
+        values.1.push(tile.patch.1);
+        lookup.insert(
+            (tile.field.clone(), tile.patch, tile.z.clone()),
+            tile.path.clone(),
+        );
+        stacks
+            .entry((tile.field.clone(), tile.patch))
+            .or_default()
+            .push(tile);
+    }
+
+    let steps: HashMap<Vec<String>, (i64, i64)> = patch_values
+        .into_iter()
+        .map(|(field, (mut xs, mut ys))| (field, (patch_step(&mut xs), patch_step(&mut ys))))
+        .collect();
+
+    stacks
+        .into_iter()
+        .map(|((field, (x, y)), mut tiles)| {
+            tiles.sort_by(|a, b| {
+                let z = |tile: &Tile| tile.z.parse::<f64>().unwrap_or(f64::NAN);
+                z(a).total_cmp(&z(b))
+            });
+            let (step_x, step_y) = steps[&field];
+
+            let images = tiles
+                .iter()
+                .map(|tile| {
+                    let neighbours = Neighbours::square_offsets(radius)
+                        .iter()
+                        .map(|(dx, dy)| {
+                            let key = (
+                                field.clone(),
+                                (x + *dx as i64 * step_x, y + *dy as i64 * step_y),
+                                tile.z.clone(),
+                            );
+                            lookup.get(&key).cloned()
+                        })
+                        .collect();
+                    let mut image = AnnotationImage::from_vec(tile.path.clone(), neighbours);
+                    image.z = tile.z.parse::<f64>().ok();
+                    image
+                })
+                .collect();
+            AnnotationZStack::new(images)
+        })
+        .collect()
+}
+
+fn parse_files(files: &[PathBuf], pattern: &ImportPattern, dataset_dir: &Path) -> Vec<Tile> {
+    files
+        .iter()
+        .filter_map(|path| {
+            let file_name = path.file_stem()?.to_str()?;
+            pattern.parse(file_name, relative_path(path, dataset_dir))
+        })
+        .collect()
+}
+
+pub fn scan_directory(
+    dir: &Path,
+    pattern: &ImportPattern,
+    dataset_dir: &Path,
+    radius: usize,
+) -> Result<Vec<AnnotationZStack>, String> {
+    let mut files = Vec::new();
+    collect_files(dir, &mut files)?;
+
+    Ok(build_stacks(
+        parse_files(&files, pattern, dataset_dir),
+        radius,
+    ))
+}
+
+pub fn import_directory(
+    dir: &Path,
+    options: &ImportOptions,
+    output: &Path,
+) -> Result<usize, String> {
+    let pattern = ImportPattern::new(&options.pattern)?;
+    let dir = fs::canonicalize(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+    let dataset_dir = output
+        .parent()
+        .filter(|x| !x.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."));
+    let dataset_dir =
+        fs::canonicalize(dataset_dir).map_err(|e| format!("{}: {}", dataset_dir.display(), e))?;
+
+    let stacks = match options.tile_size {
+        Some(tile_size) => {
+            let patch_dir = tiling::patch_dir(output);
+            tiling::tile_directory(
+                &dir,
+                &pattern,
+                tile_size,
+                options.tile_overlap,
+                &dataset_dir,
+                &patch_dir,
+                options.neighbourhood_radius,
+            )?
+        }
+        None => scan_directory(&dir, &pattern, &dataset_dir, options.neighbourhood_radius)?,
+    };
+    if stacks.is_empty() {
+        return Err(format!("no file in {} matches the pattern", dir.display()));
+    }
+
+    State::save_file(output.to_path_buf(), &stacks)?;
+    Ok(stacks.len())
+}
+
+// Dataset file inside the scanned folder that does not overwrite an existing one
+pub fn default_output_path(dir: &Path) -> PathBuf {
+    let name = dir
+        .file_name()
+        .and_then(|x| x.to_str())
+        .unwrap_or("dataset")
+        .to_string();
+
+    let mut path = dir.join(format!("{}.json", name));
+    let mut counter = 1;
+    while path.exists() {
+        path = dir.join(format!("{}_{}.json", name, counter));
+        counter += 1;
+    }
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::DEFAULT_IMPORT_PATTERN;
+
+    // Planes of the fields from the example in the README, rows 0 and 75, columns 1125 to 1275
+    fn readme_tiles() -> Vec<Tile> {
+        let pattern = ImportPattern::new(DEFAULT_IMPORT_PATTERN).unwrap();
+        let mut tiles = Vec::new();
+        for (image, z) in [("03989", "5703"), ("03987", "5498"), ("03988", "5566")] {
+            for row in [0, 75] {
+                for column in [1125, 1200, 1275] {
+                    let name = format!("I{}_X008_Y026_Z{}_{}_{}", image, z, row, column);
+                    let path = format!("img/31/I{}/{}.jpg", image, name);
+                    tiles.push(pattern.parse(&name, path).unwrap());
+                }
+            }
+        }
+        tiles
+    }
+
+    fn image_name(path: &str) -> &str {
+        path.rsplit('/').next().unwrap()
+    }
+
+    #[test]
+    fn tiles_are_grouped_by_position() {
+        let stacks = build_stacks(readme_tiles(), 1);
+        assert_eq!(stacks.len(), 6);
+
+        let stack = stacks
+            .iter()
+            .find(|stack| stack.images[0].image_path.ends_with("_0_1200.jpg"))
+            .unwrap();
+        let names: Vec<&str> = stack
+            .images
+            .iter()
+            .map(|image| image_name(&image.image_path))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "I03987_X008_Y026_Z5498_0_1200.jpg",
+                "I03988_X008_Y026_Z5566_0_1200.jpg",
+                "I03989_X008_Y026_Z5703_0_1200.jpg",
+            ]
+        );
+        for stack in &stacks {
+            assert_eq!(stack.images.len(), 3);
+            let position = |path: &str| image_name(path).splitn(5, '_').last().unwrap().to_string();
+            let first = position(&stack.images[0].image_path);
+            assert!(stack
+                .images
+                .iter()
+                .all(|image| position(&image.image_path) == first));
+        }
+    }
+
+    #[test]
+    fn neighbours_follow_the_documented_layout() {
+        let stacks = build_stacks(readme_tiles(), 1);
+        let image = stacks
+            .iter()
+            .flat_map(|stack| stack.images.iter())
+            .find(|image| image_name(&image.image_path) == "I03987_X008_Y026_Z5498_0_1200.jpg")
+            .unwrap();
+
+        // 0 1 2
+        // 3 - 4
+        // 5 6 7
+        let neighbours: Vec<Option<&str>> = image
+            .neighbours
+            .iter()
+            .map(|(_, path)| path.map(|x| image_name(x)))
+            .collect();
+        assert_eq!(
+            neighbours,
+            [
+                None,
+                None,
+                None,
+                Some("I03987_X008_Y026_Z5498_0_1125.jpg"),
+                Some("I03987_X008_Y026_Z5498_0_1275.jpg"),
+                Some("I03987_X008_Y026_Z5498_75_1125.jpg"),
+                Some("I03987_X008_Y026_Z5498_75_1200.jpg"),
+                Some("I03987_X008_Y026_Z5498_75_1275.jpg"),
+            ]
+        );
+    }
+}
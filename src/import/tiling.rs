@@ -0,0 +1,155 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use image::GenericImageView;
+
+use super::{build_stacks, collect_files, relative_path, ImportPattern, Tile};
+use crate::constants::PATCH_DIR_SUFFIX;
+use crate::state::AnnotationZStack;
+
+// Folder next to the dataset file the generated patches are written to
+pub fn patch_dir(output: &Path) -> PathBuf {
+    let stem = output
+        .file_stem()
+        .and_then(|x| x.to_str())
+        .unwrap_or("dataset");
+    output.with_file_name(format!("{}{}", stem, PATCH_DIR_SUFFIX))
+}
+
+fn patch_positions(length: u32, tile_size: u32, stride: u32) -> Vec<u32> {
+    if length < tile_size {
+        return Vec::new();
+    }
+    (0..=(length - tile_size) / stride)
+        .map(|index| index * stride)
+        .collect()
+}
+
+// Cuts every full-field image into square patches, patches that would cross the image border are dropped
+pub fn tile_directory(
+    dir: &Path,
+    pattern: &ImportPattern,
+    tile_size: u32,
+    tile_overlap: u32,
+    dataset_dir: &Path,
+    patch_dir: &Path,
+    radius: usize,
+) -> Result<Vec<AnnotationZStack>, String> {
+    if tile_size == 0 || tile_overlap >= tile_size {
+        return Err(format!(
+            "overlap {} must be smaller than the tile size {}",
+            tile_overlap, tile_size
+        ));
+    }
+    let stride = tile_size - tile_overlap;
+
+    let mut files = Vec::new();
+    collect_files(dir, &mut files)?;
+    fs::create_dir_all(patch_dir).map_err(|e| format!("{}: {}", patch_dir.display(), e))?;
+
+    let mut patches = Vec::new();
+    for path in files {
+        let file_name = match path.file_stem().and_then(|x| x.to_str()) {
+            Some(file_name) => file_name.to_string(),
+            None => continue,
+        };
+        let full_field = match pattern.parse(&file_name, path.to_string_lossy().to_string()) {
+            Some(full_field) => full_field,
+            None => continue,
+        };
+        let extension = path.extension().and_then(|x| x.to_str()).unwrap_or("png");
+
+        let image = image::open(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let (width, height) = image.dimensions();
+        for y in patch_positions(height, tile_size, stride) {
+            for x in patch_positions(width, tile_size, stride) {
+                // Named like the tiles of `DEFAULT_IMPORT_PATTERN`, row before column
+                let patch_path = patch_dir.join(format!("{}_{}_{}.{}", file_name, y, x, extension));
+                image
+                    .crop_imm(x, y, tile_size, tile_size)
+                    .save(&patch_path)
+                    .map_err(|e| format!("{}: {}", patch_path.display(), e))?;
+
+                patches.push(Tile {
+                    path: relative_path(&patch_path, dataset_dir),
+                    field: full_field.field.clone(),
+                    patch: (x as i64, y as i64),
+                    z: full_field.z.clone(),
+                });
+            }
+        }
+        println!("Tiled {}", path.display());
+    }
+
+    let mut stacks = build_stacks(patches, radius);
+    stacks
+        .iter_mut()
+        .for_each(|stack| stack.tile_overlap = Some(tile_overlap));
+    Ok(stacks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::{DEFAULT_IMPORT_PATTERN, DEFAULT_TILE_PATTERN};
+    use crate::import::scan_directory;
+
+    fn neighbourhoods(stacks: &[AnnotationZStack]) -> Vec<(String, Vec<Option<String>>)> {
+        let mut neighbourhoods: Vec<(String, Vec<Option<String>>)> = stacks
+            .iter()
+            .flat_map(|stack| stack.images.iter())
+            .map(|image| {
+                (
+                    image.image_path.clone(),
+                    image
+                        .neighbours
+                        .iter()
+                        .map(|(_, path)| path.map(|x| x.to_string()))
+                        .collect(),
+                )
+            })
+            .collect();
+        neighbourhoods.sort();
+        neighbourhoods
+    }
+
+    #[test]
+    fn patches_are_imported_with_the_default_pattern() {
+        let dir = std::env::temp_dir().join(format!("tiling_round_trip_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let images = dir.join("images");
+        fs::create_dir_all(&images).unwrap();
+        // 3 columns and 2 rows of patches on two focus levels
+        for z in ["5498", "5566"] {
+            image::RgbImage::new(12, 8)
+                .save(images.join(format!("I03987_X008_Y026_Z{}.png", z)))
+                .unwrap();
+        }
+
+        let patches = patch_dir(&dir.join("dataset.json"));
+        let pattern = ImportPattern::new(DEFAULT_TILE_PATTERN).unwrap();
+        let tiled = tile_directory(&images, &pattern, 4, 0, &dir, &patches, 1).unwrap();
+        let pattern = ImportPattern::new(DEFAULT_IMPORT_PATTERN).unwrap();
+        let imported = scan_directory(&patches, &pattern, &dir, 1).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(tiled.len(), 6);
+        assert_eq!(neighbourhoods(&imported), neighbourhoods(&tiled));
+
+        // 0 1 2
+        // 3 - 4
+        // 5 6 7
+        let (_, neighbours) = neighbourhoods(&tiled)
+            .into_iter()
+            .find(|(path, _)| path.ends_with("Z5498_0_0.png"))
+            .unwrap();
+        let name = |position: &str| {
+            Some(format!(
+                "dataset_patches/I03987_X008_Y026_Z5498_{}.png",
+                position
+            ))
+        };
+        assert_eq!(neighbours[4], name("0_4"));
+        assert_eq!(neighbours[6], name("4_0"));
+    }
+}
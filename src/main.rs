@@ -1,7 +1,12 @@
 #[macro_use]
 extern crate derive_builder;
 
+mod cli;
+mod config;
 mod constants;
+mod export;
+mod imaging;
+mod import;
 mod state;
 mod ui;
 
@@ -9,13 +14,29 @@ pub use crate::constants::MARGIN_BOTTOM;
 pub use crate::state::AnnotationImage;
 pub use crate::ui::ImageUI;
 
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+
 use adw::{prelude::*, Application};
-use gtk::gio::SimpleAction;
-use gtk::glib::{MainContext, PRIORITY_DEFAULT};
+use gtk::gdk::{DragAction, Key, ModifierType};
+use gtk::gio::{File, SimpleAction};
+use gtk::glib::{MainContext, VariantTy, PRIORITY_DEFAULT};
+use gtk::{
+    DropTarget, EventControllerKey, EventControllerMotion, EventControllerScroll,
+    EventControllerScrollFlags, GestureDrag, Inhibit, PropagationPhase,
+};
 
-use state::{Message, State, UIMessage};
+use config::Config;
+use constants::{COMPARE_HOLD_KEY, REGION_MIN_SIZE, SCROLL_FOCUS_STEP, ZOOM_STEP};
+use imaging::Overlay;
+use state::{FocusJump, Message, Tabs, UIMessage};
 
 fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Some(exit_code) = cli::run(&args) {
+        std::process::exit(exit_code);
+    }
+
     let application = Application::builder()
         .application_id("org.kuchelmeister.FocusAnnotator")
         .build();
@@ -33,7 +54,7 @@ fn main() {
 fn build_ui(app: &Application) {
     let (sender, receiver) = MainContext::channel(PRIORITY_DEFAULT);
 
-    let mut state = State::new();
+    let tabs = Rc::new(RefCell::new(Tabs::new()));
     let image_ui = ImageUI::new(app, sender.clone());
 
     //////////////////
@@ -76,6 +97,56 @@ fn build_ui(app: &Application) {
             .unwrap();
     });
 
+    image_ui.pin_button.connect_clicked(|button| {
+        button
+            .activate_action("win.pin_focus", None)
+            .expect("The action does not exist.");
+    });
+
+    image_ui.mark_region_button.connect_clicked(|button| {
+        button
+            .activate_action("win.mark_region", None)
+            .expect("The action does not exist.");
+    });
+
+    image_ui.remove_region_button.connect_clicked(|button| {
+        button
+            .activate_action("win.remove_region", None)
+            .expect("The action does not exist.");
+    });
+
+    let _sender = sender.clone();
+    image_ui.split_view_toggle_button.connect_toggled(move |x| {
+        _sender
+            .send(Message::UI(UIMessage::ShowSplitView(x.is_active())))
+            .unwrap();
+    });
+
+    let _sender = sender.clone();
+    image_ui
+        .all_in_focus_toggle_button
+        .connect_toggled(move |x| {
+            _sender
+                .send(Message::UI(UIMessage::ShowAllInFocus(x.is_active())))
+                .unwrap();
+        });
+
+    let _sender = sender.clone();
+    image_ui.flicker_toggle_button.connect_toggled(move |x| {
+        _sender
+            .send(Message::UI(UIMessage::Flicker(x.is_active())))
+            .unwrap();
+    });
+
+    let _sender = sender.clone();
+    image_ui
+        .filmstrip_sharpness_checkbox
+        .connect_toggled(move |x| {
+            _sender
+                .send(Message::UI(UIMessage::ShowSharpness(x.is_active())))
+                .unwrap();
+        });
+
     let _sender = sender.clone();
     image_ui.open_button.connect_clicked(move |_| {
         _sender
@@ -83,6 +154,286 @@ fn build_ui(app: &Application) {
             .unwrap();
     });
 
+    let _sender = sender.clone();
+    let import_folder = SimpleAction::new("import_folder", None);
+    import_folder.connect_activate(move |_, _| {
+        _sender
+            .send(Message::UI(UIMessage::OpenImportChooser))
+            .unwrap();
+    });
+
+    let _sender = sender.clone();
+    let export_focus_maps = SimpleAction::new("export_focus_maps", None);
+    export_focus_maps.connect_activate(move |_, _| {
+        _sender
+            .send(Message::UI(UIMessage::OpenFocusMapExportChooser))
+            .unwrap();
+    });
+
+    let _sender = sender.clone();
+    let save = SimpleAction::new("save", None);
+    save.connect_activate(move |_, _| {
+        _sender.send(Message::Save).unwrap();
+    });
+
+    let _sender = sender.clone();
+    let save_as = SimpleAction::new("save_as", None);
+    save_as.connect_activate(move |_, _| {
+        _sender
+            .send(Message::UI(UIMessage::OpenSaveAsChooser))
+            .unwrap();
+    });
+
+    let _sender = sender.clone();
+    let preferences = SimpleAction::new("preferences", None);
+    preferences.connect_activate(move |_, _| {
+        _sender
+            .send(Message::UI(UIMessage::OpenPreferences))
+            .unwrap();
+    });
+
+    let _sender = sender.clone();
+    let open_in_tab = SimpleAction::new("open_in_tab", None);
+    open_in_tab.connect_activate(move |_, _| {
+        _sender
+            .send(Message::UI(UIMessage::OpenTabFileChooser))
+            .unwrap();
+    });
+
+    let _tabs = tabs.clone();
+    let _image_ui = image_ui.clone();
+    let _tab_view = image_ui.tab_view.clone();
+    let close_tab = SimpleAction::new("close_tab", None);
+    close_tab.connect_activate(move |_, _| {
+        if let Some(page) = _tab_view.selected_page() {
+            let index = _tab_view.page_position(&page) as usize;
+            if let Some(state) = _tabs.borrow().states().get(index) {
+                _image_ui.close_tab(index, state);
+            }
+        }
+    });
+
+    //////////
+    // TABS //
+    //////////
+    let _sender = sender.clone();
+    let _tabs_updating = image_ui.tabs_updating.clone();
+    image_ui
+        .tab_view
+        .connect_selected_page_notify(move |tab_view| {
+            if let (false, Some(page)) = (_tabs_updating.get(), tab_view.selected_page()) {
+                let index = tab_view.page_position(&page) as usize;
+                _sender.send(Message::SelectTab(index)).unwrap();
+            }
+        });
+
+    // Pages are only removed by `ImageUI::update_tabs` once the dataset is closed
+    let _tabs = tabs.clone();
+    let _image_ui = image_ui.clone();
+    let _tabs_updating = image_ui.tabs_updating.clone();
+    image_ui.tab_view.connect_close_page(move |tab_view, page| {
+        if _tabs_updating.get() {
+            tab_view.close_page_finish(page, true);
+        } else {
+            let index = tab_view.page_position(page) as usize;
+            tab_view.close_page_finish(page, false);
+            if let Some(state) = _tabs.borrow().states().get(index) {
+                _image_ui.close_tab(index, state);
+            }
+        }
+        Inhibit(true)
+    });
+
+    // Closing the window quits, the annotations of a read-only dataset are lost unless they are
+    // saved to another file
+    let _tabs = tabs.clone();
+    let _image_ui = image_ui.clone();
+    let discard_confirmed = Rc::new(Cell::new(false));
+    image_ui.window.connect_close_request(move |window| {
+        if discard_confirmed.get() {
+            return Inhibit(false);
+        }
+        let tabs = _tabs.borrow();
+        let unsaved = tabs
+            .states()
+            .iter()
+            .position(|state| state.is_read_only() && state.is_dirty());
+        match unsaved {
+            Some(index) => {
+                let _window = window.clone();
+                let _discard_confirmed = discard_confirmed.clone();
+                let quit = move || {
+                    _discard_confirmed.set(true);
+                    _window.close();
+                };
+                _image_ui.confirm_discard(
+                    index,
+                    &tabs.states()[index],
+                    "Quit Without Saving",
+                    quit,
+                );
+                Inhibit(true)
+            }
+            None => Inhibit(false),
+        }
+    });
+
+    // Datasets dropped onto the window open in a new tab, other files are refused
+    let _sender = sender.clone();
+    let drop_target = DropTarget::new(File::static_type(), DragAction::COPY);
+    drop_target.connect_drop(move |_, value, _, _| match value.get::<File>() {
+        Ok(file)
+            if file.path().map_or(false, |x| {
+                x.is_file() && x.extension().map_or(false, |x| x == "json")
+            }) =>
+        {
+            _sender.send(Message::OpenFileInTab(file)).unwrap();
+            true
+        }
+        _ => false,
+    });
+    image_ui.window.add_controller(&drop_target);
+
+    let _sender = sender.clone();
+    let open_recent = SimpleAction::new("open_recent", Some(VariantTy::STRING));
+    open_recent.connect_activate(move |_, parameter| {
+        if let Some(path) = parameter.and_then(|x| x.get::<String>()) {
+            _sender
+                .send(Message::OpenFile(File::for_path(path)))
+                .unwrap();
+        }
+    });
+
+    let _sender = sender.clone();
+    let edit_shortcuts = SimpleAction::new("edit_shortcuts", None);
+    edit_shortcuts.connect_activate(move |_, _| {
+        _sender
+            .send(Message::UI(UIMessage::OpenShortcutsDialog))
+            .unwrap();
+    });
+
+    let _sender = sender.clone();
+    let export_all_in_focus = SimpleAction::new("export_all_in_focus", None);
+    export_all_in_focus.connect_activate(move |_, _| {
+        _sender
+            .send(Message::UI(UIMessage::OpenAllInFocusExportChooser))
+            .unwrap();
+    });
+
+    let _sender = sender.clone();
+    let tile_folder = SimpleAction::new("tile_folder", None);
+    tile_folder.connect_activate(move |_, _| {
+        _sender
+            .send(Message::UI(UIMessage::OpenTileChooser))
+            .unwrap();
+    });
+
+    /////////////
+    // REGIONS //
+    /////////////
+    let region_drag = GestureDrag::new();
+    let _image_ui = image_ui.clone();
+    region_drag.connect_drag_update(move |gesture, offset_x, offset_y| {
+        if let Some((x, y)) = gesture.start_point() {
+            _image_ui.region_drawing.borrow_mut().drag = Some((x, y, offset_x, offset_y));
+            _image_ui.region_area.queue_draw();
+        }
+    });
+
+    let _image_ui = image_ui.clone();
+    let _sender = sender.clone();
+    region_drag.connect_drag_end(move |gesture, offset_x, offset_y| {
+        _image_ui.region_drawing.borrow_mut().drag = None;
+        _image_ui.region_area.queue_draw();
+        if let Some((x, y)) = gesture.start_point() {
+            if offset_x.abs() < REGION_MIN_SIZE && offset_y.abs() < REGION_MIN_SIZE {
+                let region_index = _image_ui.region_at(x, y);
+                _sender.send(Message::SelectRegion(region_index)).unwrap();
+            } else if let Some((x, y, width, height)) =
+                _image_ui.drag_to_region(x, y, offset_x, offset_y)
+            {
+                _sender
+                    .send(Message::AddRegion(x, y, width, height))
+                    .unwrap();
+            }
+        }
+    });
+    image_ui.region_area.add_controller(&region_drag);
+
+    /////////////////////
+    // DISPLAY CONTROLS //
+    /////////////////////
+    let _image_ui = image_ui.clone();
+    let _sender = sender.clone();
+    let send_display_settings = Rc::new(move || {
+        _sender
+            .send(Message::DisplaySettingsChanged(
+                _image_ui.get_display_settings(),
+            ))
+            .unwrap();
+    });
+
+    for scale in [
+        &image_ui.brightness_scale,
+        &image_ui.contrast_scale,
+        &image_ui.gamma_scale,
+    ] {
+        let _send_display_settings = send_display_settings.clone();
+        scale.connect_value_changed(move |_| _send_display_settings());
+    }
+
+    let _send_display_settings = send_display_settings.clone();
+    image_ui
+        .auto_levels_checkbox
+        .connect_toggled(move |_| _send_display_settings());
+
+    let _send_display_settings = send_display_settings.clone();
+    image_ui
+        .channel_dropdown
+        .connect_selected_notify(move |_| _send_display_settings());
+
+    let _image_ui = image_ui.clone();
+    let _sender = sender.clone();
+    image_ui.overlay_dropdown.connect_selected_notify(move |_| {
+        _sender
+            .send(Message::OverlayChanged(_image_ui.get_overlay()))
+            .unwrap();
+    });
+
+    let _sender = sender.clone();
+    image_ui.align_planes_checkbox.connect_toggled(move |x| {
+        _sender.send(Message::AlignPlanes(x.is_active())).unwrap();
+    });
+
+    let _image_ui = image_ui.clone();
+    image_ui
+        .reset_display_button
+        .connect_clicked(move |_| _image_ui.reset_display_controls());
+
+    let _image_ui = image_ui.clone();
+    let _sender = sender.clone();
+    let send_neighbour_settings = Rc::new(move || {
+        _sender
+            .send(Message::NeighbourSettingsChanged(
+                _image_ui.get_neighbour_settings(),
+            ))
+            .unwrap();
+    });
+
+    for checkbox in [
+        &image_ui.mosaic_checkbox,
+        &image_ui.outline_centre_checkbox,
+        &image_ui.own_focus_checkbox,
+    ] {
+        let _send_neighbour_settings = send_neighbour_settings.clone();
+        checkbox.connect_toggled(move |_| _send_neighbour_settings());
+    }
+
+    let _send_neighbour_settings = send_neighbour_settings.clone();
+    image_ui
+        .neighbourhood_dropdown
+        .connect_selected_notify(move |_| _send_neighbour_settings());
+
     ////////////////////////
     // Keyboard Shortcuts //
     ////////////////////////
@@ -133,23 +484,235 @@ fn build_ui(app: &Application) {
         _sender.send(Message::PreviousImage).unwrap();
     });
 
+    let _sender = sender.clone();
+    let pin_focus = SimpleAction::new("pin_focus", None);
+    pin_focus.connect_activate(move |_, _| {
+        _sender.send(Message::PinFocus).unwrap();
+    });
+
+    let _sender = sender.clone();
+    let toggle_split_view = SimpleAction::new("toggle_split_view", None);
+    toggle_split_view.connect_activate(move |_, _| {
+        _sender
+            .send(Message::UI(UIMessage::ToggleSplitView))
+            .unwrap();
+    });
+
+    let _sender = sender.clone();
+    let toggle_all_in_focus = SimpleAction::new("toggle_all_in_focus", None);
+    toggle_all_in_focus.connect_activate(move |_, _| {
+        _sender
+            .send(Message::UI(UIMessage::ToggleAllInFocus))
+            .unwrap();
+    });
+
+    let _sender = sender.clone();
+    let toggle_flicker = SimpleAction::new("toggle_flicker", None);
+    toggle_flicker.connect_activate(move |_, _| {
+        _sender.send(Message::UI(UIMessage::ToggleFlicker)).unwrap();
+    });
+
+    let _sender = sender.clone();
+    let toggle_gradient_overlay = SimpleAction::new("toggle_gradient_overlay", None);
+    toggle_gradient_overlay.connect_activate(move |_, _| {
+        _sender
+            .send(Message::UI(UIMessage::ToggleOverlay(Overlay::Gradient)))
+            .unwrap();
+    });
+
+    let _sender = sender.clone();
+    let toggle_sharpness_overlay = SimpleAction::new("toggle_sharpness_overlay", None);
+    toggle_sharpness_overlay.connect_activate(move |_, _| {
+        _sender
+            .send(Message::UI(UIMessage::ToggleOverlay(Overlay::Sharpness)))
+            .unwrap();
+    });
+
+    let _sender = sender.clone();
+    let toggle_difference_overlay = SimpleAction::new("toggle_difference_overlay", None);
+    toggle_difference_overlay.connect_activate(move |_, _| {
+        _sender
+            .send(Message::UI(UIMessage::ToggleOverlay(Overlay::Difference)))
+            .unwrap();
+    });
+
+    let _sender = sender.clone();
+    let mark_region = SimpleAction::new("mark_region", None);
+    mark_region.connect_activate(move |_, _| {
+        _sender.send(Message::MarkRegionFocus).unwrap();
+    });
+
+    let _sender = sender.clone();
+    let remove_region = SimpleAction::new("remove_region", None);
+    remove_region.connect_activate(move |_, _| {
+        _sender.send(Message::RemoveRegion).unwrap();
+    });
+
+    // Holding the compare key shows the pinned focus level until it is released. It is handled
+    // after the widgets, so a button that was focused with the keyboard still receives it.
+    let compare_hold_key = Key::from_name(COMPARE_HOLD_KEY);
+    let compare_key_controller = EventControllerKey::new();
+    let _sender = sender.clone();
+    compare_key_controller.connect_key_pressed(move |_, key, _, _| {
+        if Some(key) == compare_hold_key {
+            _sender.send(Message::ShowPinned(true)).unwrap();
+            Inhibit(true)
+        } else {
+            Inhibit(false)
+        }
+    });
+    let _sender = sender.clone();
+    compare_key_controller.connect_key_released(move |_, key, _, _| {
+        if Some(key) == compare_hold_key {
+            _sender.send(Message::ShowPinned(false)).unwrap();
+        }
+    });
+    image_ui.window.add_controller(&compare_key_controller);
+
+    // Digit keys jump to a z index, handled after the widgets so entries still receive them
+    let focus_digit_controller = EventControllerKey::new();
+    let _sender = sender.clone();
+    focus_digit_controller.connect_key_pressed(move |_, key, _, modifiers| {
+        match ImageUI::focus_digit(key, modifiers) {
+            Some(digit) => {
+                _sender
+                    .send(Message::UI(UIMessage::FocusDigit(digit)))
+                    .unwrap();
+                Inhibit(true)
+            }
+            _ => Inhibit(false),
+        }
+    });
+    image_ui.window.add_controller(&focus_digit_controller);
+
+    // Scrolling over the image changes the focus level like the fine focus of a microscope,
+    // with Control pressed it zooms, with Shift it pans the zoomed image up and down and with
+    // Shift and Control sideways
+    let pointer = Rc::new(Cell::new((0.0, 0.0)));
+    let image_motion_controller = EventControllerMotion::new();
+    let _pointer = pointer.clone();
+    image_motion_controller.connect_motion(move |_, x, y| _pointer.set((x, y)));
+    image_ui
+        .image_scrolled_window
+        .add_controller(&image_motion_controller);
+
+    let image_scroll_controller = EventControllerScroll::new(EventControllerScrollFlags::VERTICAL);
+    image_scroll_controller.set_propagation_phase(PropagationPhase::Capture);
+    let scrolled = Cell::new(0.0);
+    let _sender = sender.clone();
+    let _image_scrolled_window = image_ui.image_scrolled_window.clone();
+    image_scroll_controller.connect_scroll(move |controller, _, dy| {
+        let modifiers = controller.current_event_state();
+        if modifiers.contains(ModifierType::SHIFT_MASK) {
+            let adjustment = match modifiers.contains(ModifierType::CONTROL_MASK) {
+                true => _image_scrolled_window.hadjustment(),
+                false => _image_scrolled_window.vadjustment(),
+            };
+            // Same distance per wheel step as a scrolled window uses
+            adjustment.set_value(adjustment.value() + dy * adjustment.page_size().powf(2.0 / 3.0));
+            return Inhibit(true);
+        }
+        if modifiers.contains(ModifierType::CONTROL_MASK) {
+            let (x, y) = pointer.get();
+            _sender
+                .send(Message::UI(UIMessage::Zoom(ZOOM_STEP.powf(-dy), x, y)))
+                .unwrap();
+            return Inhibit(true);
+        }
+
+        // Scrolling up moves up in the focus stack, partial trackpad steps add up
+        let total = scrolled.get() - dy / SCROLL_FOCUS_STEP;
+        let steps = total.trunc();
+        scrolled.set(total - steps);
+        for _ in 0..steps.abs() as usize {
+            let message = match steps > 0.0 {
+                true => UIMessage::IncrementFocus,
+                false => UIMessage::DecrementFocus,
+            };
+            _sender.send(Message::UI(message)).unwrap();
+        }
+        Inhibit(true)
+    });
+    image_ui
+        .image_scrolled_window
+        .add_controller(&image_scroll_controller);
+
+    let _sender = sender.clone();
+    let reset_zoom = SimpleAction::new("reset_zoom", None);
+    reset_zoom.connect_activate(move |_, _| {
+        _sender.send(Message::UI(UIMessage::ResetZoom)).unwrap();
+    });
+    image_ui.window.add_action(&reset_zoom);
+
+    for (name, jump) in [
+        ("focus_first", FocusJump::First),
+        ("focus_last", FocusJump::Last),
+        ("focus_page_up", FocusJump::PageUp),
+        ("focus_page_down", FocusJump::PageDown),
+        ("focus_sharpest", FocusJump::Sharpest),
+        ("focus_marked", FocusJump::Marked),
+    ] {
+        let _sender = sender.clone();
+        let action = SimpleAction::new(name, None);
+        action.connect_activate(move |_, _| {
+            _sender
+                .send(Message::UI(UIMessage::JumpFocus(jump)))
+                .unwrap();
+        });
+        image_ui.window.add_action(&action);
+    }
+
     image_ui.window.add_action(&action_toggle_neighbour);
     image_ui.window.add_action(&action_focus_scale_increment);
     image_ui.window.add_action(&action_focus_scale_decrement);
     image_ui.window.add_action(&mark_focus);
     image_ui.window.add_action(&skip_focus);
     image_ui.window.add_action(&back_focus);
+    image_ui.window.add_action(&import_folder);
+    image_ui.window.add_action(&tile_folder);
+    image_ui.window.add_action(&export_focus_maps);
+    image_ui.window.add_action(&export_all_in_focus);
+    image_ui.window.add_action(&edit_shortcuts);
+    image_ui.window.add_action(&open_recent);
+    image_ui.window.add_action(&open_in_tab);
+    image_ui.window.add_action(&preferences);
+    image_ui.window.add_action(&save);
+    image_ui.window.add_action(&save_as);
+    image_ui.window.add_action(&close_tab);
+    image_ui.window.add_action(&toggle_all_in_focus);
+    image_ui.window.add_action(&pin_focus);
+    image_ui.window.add_action(&toggle_split_view);
+    image_ui.window.add_action(&toggle_flicker);
+    image_ui.window.add_action(&toggle_gradient_overlay);
+    image_ui.window.add_action(&toggle_sharpness_overlay);
+    image_ui.window.add_action(&toggle_difference_overlay);
+    image_ui.window.add_action(&mark_region);
+    image_ui.window.add_action(&remove_region);
 
-    let _sender = sender.clone();
+    // Messages are no longer received once the main loop stopped, the datasets are saved and
+    // unlocked right away when the application shuts down
+    let _tabs = tabs.clone();
+    let _image_ui = image_ui.clone();
     app.connect_shutdown(move |_| {
-        _sender.send(Message::Quit).unwrap();
+        let mut tabs = _tabs.borrow_mut();
+        tabs.update(&Message::Quit);
+        _image_ui.refresh(&Message::Quit, tabs.current());
     });
 
+    // The stored preferences replace the defaults the widgets and tabs start with
+    let preferences = Config::load().preferences;
+    image_ui.apply_start_preferences(&preferences);
+    sender
+        .send(Message::PreferencesChanged(preferences))
+        .unwrap();
+
     image_ui.show();
     receiver.attach(None, move |msg| {
         eprintln!("Received message: {:?}", msg);
-        state.update(&msg);
-        image_ui.refresh(&msg, &state);
+        tabs.borrow_mut().update(&msg);
+        let tabs = tabs.borrow();
+        image_ui.update_tabs(&tabs);
+        image_ui.refresh(&msg, tabs.current());
         Continue(true)
     });
 }
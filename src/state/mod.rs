@@ -1,13 +1,29 @@
-use std::fs;
-use std::io::Write;
-use std::path::{Path, PathBuf};
-use std::{collections::HashMap, time::Instant};
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Component, Path, PathBuf};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    hash::{Hash, Hasher},
+    process,
+    time::{Instant, SystemTime},
+};
 
 use gtk::{gio::File, prelude::FileExt};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
-use crate::constants::{ANNOTATION_CACHE_FILE_ENDING, NONE_STRING_OPTION};
+use crate::config::{InitialFocus, Preferences};
+use crate::constants::{
+    ANNOTATION_CACHE_FILE_ENDING, LOCK_FILE_SUFFIX, OUTPUT_FILE_SEPARATOR, PARTIAL_FILE_SUFFIX,
+    Z_FILE_NAME_PREFIX,
+};
+use crate::export::{self, FocusMapOptions};
+use crate::imaging::{DisplaySettings, NeighbourSettings, Overlay};
+use crate::import::{self, ImportOptions};
+
+mod tabs;
+
+pub use tabs::Tabs;
 
 #[derive(Debug)]
 pub enum Message {
@@ -17,7 +33,37 @@ pub enum Message {
     PreviousImage,
     UI(UIMessage),
     OpenFile(File),
+    OpenFileInTab(File),
+    SelectTab(usize),
+    CloseTab(usize),
+    ImportFolder(PathBuf, ImportOptions),
+    ExportFocusMaps(PathBuf, FocusMapOptions),
+    ExportAllInFocus(PathBuf),
+    AlignPlanes(bool),
+    // Drift of every plane of a stack, estimated by the UI from the planes it loaded
+    PlaneShifts(usize, Result<Vec<(f64, f64)>, String>),
+    PreferencesChanged(Preferences),
+    Save,
+    SaveAs(PathBuf),
+    Autosave,
+    // Reads the dataset again after it was changed by another program, `Merge` keeps the
+    // annotations of this session
+    Reload,
+    Merge,
+    // Takes the lock of a dataset that was opened read-only from the session holding it
+    TakeOverLock,
     SkipMarkedToogled(bool),
+    DisplaySettingsChanged(DisplaySettings),
+    NeighbourSettingsChanged(NeighbourSettings),
+    OverlayChanged(Overlay),
+    // Rectangle x, y, width and height relative to the tile size
+    AddRegion(f64, f64, f64, f64),
+    SelectRegion(Option<usize>),
+    MarkRegionFocus,
+    RemoveRegion,
+    PinFocus,
+    ShowPinned(bool),
+    TogglePinned,
     Quit,
 }
 
@@ -25,11 +71,42 @@ pub enum Message {
 #[derive(Debug)]
 pub enum UIMessage {
     OpenFileChooser,
+    OpenTabFileChooser,
+    OpenSaveAsChooser,
+    OpenImportChooser,
+    OpenTileChooser,
+    OpenFocusMapExportChooser,
+    OpenAllInFocusExportChooser,
+    OpenShortcutsDialog,
+    OpenPreferences,
+    ToggleAllInFocus,
+    ShowAllInFocus(bool),
     RefreshImages,
     ToggleGrid,
     DecrementFocus,
     IncrementFocus,
+    JumpFocus(FocusJump),
+    FocusDigit(u32),
+    // Zoom factor and the position in the image view that stays in place
+    Zoom(f64, f64, f64),
+    ResetZoom,
     ShowGrid(bool),
+    ToggleSplitView,
+    ShowSplitView(bool),
+    ToggleFlicker,
+    Flicker(bool),
+    ShowSharpness(bool),
+    ToggleOverlay(Overlay),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FocusJump {
+    First,
+    Last,
+    PageUp,
+    PageDown,
+    Sharpest,
+    Marked,
 }
 
 #[derive(Debug, Clone)]
@@ -41,11 +118,44 @@ pub struct State {
     annotation_cache: Vec<LightAnnotation>,
     pub root_path: Option<String>,
     skip_marked: bool,
+    display_settings: DisplaySettings,
+    pinned_image_index: Option<usize>,
+    show_pinned: bool,
+    neighbour_settings: NeighbourSettings,
+    overlay: Overlay,
+    selected_region: Option<usize>,
+    align_planes: bool,
+    initial_focus: InitialFocus,
+    // The current stack starts at its sharpest level once the UI found it
+    sharpest_focus_pending: bool,
+    separate_output: bool,
+    output_dir: Option<PathBuf>,
+    // File the dataset is saved to instead of the opened file
+    output_path: Option<PathBuf>,
+    dirty: bool,
+    // Save file as it was last read or written, to notice when another program changes it
+    disk_version: Option<FileVersion>,
+    // The save file was changed by another program and the dataset was not saved over it
+    conflict: bool,
+    // Lock file of the save file while this session holds it
+    lock_path: Option<PathBuf>,
+    // Session holding the lock when the dataset was opened, the dataset is read-only then
+    locked_by: Option<LockInfo>,
+    // Stacks whose planes could not be registered, they are not tried again
+    registration_failures: HashSet<usize>,
+    // Stack and image index of every image path and page in the dataset, the planes of a
+    // multi-page file share its path
+    image_lookup: HashMap<(String, Option<usize>), (usize, usize)>,
+    // Problem with the last message that is shown to the user
+    error: Option<String>,
 }
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LightAnnotation {
     stack_index: usize,
     focus_image_index: usize,
+    // Regions of the stack after they were changed, not set when only the focus was marked
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    regions: Option<Vec<Region>>,
 }
 
 impl LightAnnotation {
@@ -53,6 +163,7 @@ impl LightAnnotation {
         LightAnnotation {
             stack_index,
             focus_image_index,
+            regions: None,
         }
     }
 }
@@ -67,24 +178,109 @@ impl State {
             annotation_cache: Vec::new(),
             root_path: None,
             skip_marked: true,
+            display_settings: DisplaySettings::default(),
+            pinned_image_index: None,
+            show_pinned: false,
+            neighbour_settings: NeighbourSettings::default(),
+            overlay: Overlay::None,
+            selected_region: None,
+            align_planes: false,
+            initial_focus: InitialFocus::Keep,
+            sharpest_focus_pending: false,
+            separate_output: false,
+            output_dir: None,
+            output_path: None,
+            dirty: false,
+            disk_version: None,
+            conflict: false,
+            lock_path: None,
+            locked_by: None,
+            registration_failures: HashSet::new(),
+            image_lookup: HashMap::new(),
+            error: None,
+        }
+    }
+
+    // Empty state that uses the same settings as `other`, for a new tab
+    pub fn with_settings_of(other: &State) -> Self {
+        State {
+            skip_marked: other.skip_marked,
+            display_settings: other.display_settings,
+            neighbour_settings: other.neighbour_settings,
+            overlay: other.overlay,
+            align_planes: other.align_planes,
+            initial_focus: other.initial_focus,
+            separate_output: other.separate_output,
+            output_dir: other.output_dir.clone(),
+            ..State::new()
+        }
+    }
+
+    pub fn has_file(&self) -> bool {
+        self.file_name.is_some()
+    }
+
+    pub fn get_file_name(&self) -> Option<String> {
+        self.file_name.clone()
+    }
+
+    // Whether the dataset at `path` is open, either itself, through its output file or as the file
+    // it is saved to
+    pub fn is_open(&self, path: &Path) -> bool {
+        if !self.has_file() {
+            return false;
         }
+        let file_path = self.get_file_path();
+        file_path.as_deref() == Some(path)
+            || self.get_save_path().as_deref() == Some(path)
+            || file_path == self.get_output_path(path)
     }
 
     pub fn get_focus_image_index(&self) -> Option<usize> {
         return self.focus_image_index;
     }
 
+    pub fn get_error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+
+    fn set_error(&mut self, error: String) {
+        eprintln!("{}", error);
+        self.error = Some(error);
+    }
+
     pub fn update(&mut self, msg: &Message) {
         match msg {
             Message::OpenFile(file) => {
-                if self.get_file_path() != None {
-                    // Save before opening a new file
-                    self.save();
-                    self.delete_tmp_file();
+                if let Err(e) = self.open(file) {
+                    self.set_error(format!("Could not open the dataset: {}", e));
+                }
+            }
+            Message::ImportFolder(dir, options) => {
+                let output = import::default_output_path(dir);
+                match import::import_directory(dir, options, &output) {
+                    Ok(_) => self.update(&Message::OpenFile(File::for_path(output))),
+                    Err(e) => self.set_error(format!("Import of {} failed: {}", dir.display(), e)),
+                }
+            }
+            // Focus maps are exported by the UI on a worker thread, see `get_stacks`
+            Message::ExportFocusMaps(_, _) => {}
+            Message::ExportAllInFocus(path) => {
+                match (self.root_path.clone(), self.get_current_focus_stack()) {
+                    (Some(root_path), Some(stack)) => {
+                        match export::export_all_in_focus(
+                            stack,
+                            Path::new(&root_path),
+                            path,
+                            self.align_planes,
+                        ) {
+                            Ok(_) => eprintln!("Wrote all in focus image to {:?}", path),
+                            Err(e) => self
+                                .set_error(format!("Export of all in focus image failed: {}", e)),
+                        }
+                    }
+                    (_, _) => self.set_error("No focus stack is selected".to_string()),
                 }
-                self.open(file);
-                self.integrate_tmp_file();
-                self.delete_tmp_file();
             }
             Message::NextImage => {
                 self.skip();
@@ -92,25 +288,179 @@ impl State {
             Message::PreviousImage => {
                 self.previous();
             }
+            Message::Save => {
+                self.conflict = false;
+                self.save();
+            }
+            Message::SaveAs(path) => match State::lock_holder(path) {
+                // Its annotations would be lost when the other session saves
+                Some(holder) => self.set_error(format!(
+                    "{} is opened by {}, it is not saved over",
+                    path.display(),
+                    holder.describe()
+                )),
+                None if self.has_file() => self.save_to(path.clone()),
+                None => {}
+            },
+            Message::Autosave => {
+                // Waits for the user to resolve a conflict, a read-only dataset is only saved to
+                // another file with Save As
+                if self.dirty && !self.conflict && self.locked_by.is_none() {
+                    self.save();
+                }
+            }
+            Message::Reload => self.reload(false),
+            Message::Merge => self.reload(true),
+            Message::TakeOverLock => self.take_over_lock(),
             Message::MarkFocus => {
+                self.dirty = true;
                 self.mark_focus();
                 self.save_tmp();
                 self.skip();
             }
             Message::Quit => {
                 self.save();
-                self.delete_tmp_file();
+                if !self.dirty {
+                    self.delete_tmp_file();
+                }
+                self.release_lock();
             }
             Message::FocusLevelChange(lvl) => {
+                self.sharpest_focus_pending = false;
                 self.set_focus_image_index(Some(*lvl));
             }
             Message::SkipMarkedToogled(value) => {
                 self.skip_marked = value.clone();
             }
+            Message::DisplaySettingsChanged(display_settings) => {
+                self.display_settings = *display_settings;
+            }
+            Message::NeighbourSettingsChanged(neighbour_settings) => {
+                self.neighbour_settings = *neighbour_settings;
+            }
+            Message::OverlayChanged(overlay) => {
+                self.overlay = *overlay;
+            }
+            Message::AddRegion(x, y, width, height) => {
+                let focus_image_index = self.focus_image_index;
+                if let (Some(stack), Some(focus_image_index)) =
+                    (self.get_current_focus_stack_mut(), focus_image_index)
+                {
+                    let mut region = Region::new(*x, *y, *width, *height);
+                    region.best_index = Some(focus_image_index);
+                    stack.regions.push(region);
+                    stack.update_best_z();
+                    self.selected_region = Some(stack.regions.len() - 1);
+                    self.journal_regions();
+                }
+            }
+            Message::SelectRegion(region_index) => {
+                self.selected_region = *region_index;
+            }
+            Message::MarkRegionFocus => {
+                if let (Some(region_index), Some(focus_image_index)) =
+                    (self.selected_region, self.focus_image_index)
+                {
+                    if let Some(stack) = self.get_current_focus_stack_mut() {
+                        if let Some(region) = stack.regions.get_mut(region_index) {
+                            region.best_index = Some(focus_image_index);
+                        }
+                        stack.update_best_z();
+                        self.journal_regions();
+                    }
+                }
+            }
+            Message::RemoveRegion => {
+                if let Some(region_index) = self.selected_region.take() {
+                    if let Some(stack) = self.get_current_focus_stack_mut() {
+                        if region_index < stack.regions.len() {
+                            stack.regions.remove(region_index);
+                        }
+                        self.journal_regions();
+                    }
+                }
+            }
+            Message::PinFocus => {
+                if self.pinned_image_index == self.focus_image_index {
+                    self.unpin();
+                } else {
+                    self.pinned_image_index = self.focus_image_index;
+                }
+            }
+            Message::ShowPinned(value) => {
+                self.show_pinned = *value && self.pinned_image_index.is_some();
+            }
+            Message::TogglePinned => {
+                self.show_pinned = !self.show_pinned && self.pinned_image_index.is_some();
+            }
+            Message::PlaneShifts(stack_index, shifts) => {
+                self.set_plane_shifts(*stack_index, shifts)
+            }
+            Message::AlignPlanes(value) => {
+                self.align_planes = *value;
+            }
+            Message::PreferencesChanged(preferences) => {
+                self.initial_focus = preferences.initial_focus;
+                self.separate_output = preferences.separate_output;
+                self.output_dir = preferences.output_dir.clone();
+            }
+            // Handled by `Tabs`
+            Message::OpenFileInTab(_) | Message::SelectTab(_) | Message::CloseTab(_) => {}
             Message::UI(_) => {}
         }
     }
 
+    pub fn is_aligning_planes(&self) -> bool {
+        self.align_planes
+    }
+
+    // Current stack whose drift has to be estimated to show its planes aligned, registration is
+    // slow so it is only tried once per stack
+    pub fn needs_registration(&self) -> Option<usize> {
+        let stack_index = self.stack_index.filter(|_| self.align_planes)?;
+        let stack = self.stacks.get(stack_index)?;
+        match stack.images.iter().all(|image| image.transform.is_some())
+            || self.registration_failures.contains(&stack_index)
+        {
+            true => None,
+            false => Some(stack_index),
+        }
+    }
+
+    // Stores the drift of every plane of a stack to its middle plane with the images, so it is
+    // only computed once per dataset. Shifts for a stack that no longer needs them are dropped.
+    fn set_plane_shifts(&mut self, stack_index: usize, shifts: &Result<Vec<(f64, f64)>, String>) {
+        if self.needs_registration() != Some(stack_index) {
+            return;
+        }
+        let shifts = shifts.clone().and_then(|shifts| {
+            match shifts.len() == self.stacks[stack_index].images.len() {
+                true => Ok(shifts),
+                false => Err("the number of planes changed".to_string()),
+            }
+        });
+        match shifts {
+            Ok(shifts) => {
+                let stack = &mut self.stacks[stack_index];
+                for (image, (dx, dy)) in stack.images.iter_mut().zip(shifts) {
+                    image.transform = Some(Transform::translation(dx, dy));
+                }
+                // A read-only dataset is aligned for viewing only
+                if self.locked_by.is_none() {
+                    self.dirty = true;
+                }
+            }
+            Err(e) => {
+                eprintln!("Could not register focus stack {}: {}", stack_index, e);
+                self.registration_failures.insert(stack_index);
+            }
+        }
+    }
+
+    pub fn get_display_settings(&self) -> &DisplaySettings {
+        &self.display_settings
+    }
+
     pub fn set_focus_image_index(&mut self, image_index: Option<usize>) {
         self.focus_image_index = image_index;
     }
@@ -128,6 +478,150 @@ impl State {
         }
     }
 
+    pub fn get_neighbour_settings(&self) -> &NeighbourSettings {
+        &self.neighbour_settings
+    }
+
+    pub fn get_overlay(&self) -> Overlay {
+        self.overlay
+    }
+
+    fn get_image_by_path(&self, image_path: &str, page: Option<usize>) -> Option<&AnnotationImage> {
+        self.image_lookup
+            .get(&(image_path.to_string(), page))
+            .and_then(|(stack_index, image_index)| {
+                self.stacks
+                    .get(*stack_index)
+                    .and_then(|stack| stack.images.get(*image_index))
+            })
+    }
+
+    // Path of the tile at offset (dx, dy) of an image, tiles outside of the stored
+    // neighbours are found by stepping through neighbours that are part of the dataset themselves
+    pub fn get_neighbour_path(&self, image: &AnnotationImage, dx: i32, dy: i32) -> Option<String> {
+        if dx == 0 && dy == 0 {
+            return Some(image.image_path.clone());
+        }
+        if dx.unsigned_abs() as usize <= image.neighbours.radius()
+            && dy.unsigned_abs() as usize <= image.neighbours.radius()
+        {
+            return image.neighbours.get(dx, dy).cloned();
+        }
+
+        let (step_x, step_y) = (dx.signum(), dy.signum());
+        [(step_x, step_y), (step_x, 0), (0, step_y)]
+            .iter()
+            .filter(|step| **step != (0, 0))
+            .find_map(|(step_x, step_y)| {
+                let path = self.get_neighbour_path(image, *step_x, *step_y)?;
+                let neighbour = self.get_image_by_path(&path, image.page)?;
+                self.get_neighbour_path(neighbour, dx - step_x, dy - step_y)
+            })
+    }
+
+    // Path and page of the tile at offset (dx, dy) to display, with `own_focus` neighbours are
+    // shown at the plane marked in their own focus stack if they have been annotated
+    pub fn get_displayed_neighbour(
+        &self,
+        image: &AnnotationImage,
+        dx: i32,
+        dy: i32,
+        own_focus: bool,
+    ) -> Option<(String, Option<usize>)> {
+        let path = self.get_neighbour_path(image, dx, dy)?;
+        if own_focus && (dx, dy) != (0, 0) {
+            if let Some(annotated) = self.get_annotated_image_by_path(&path, image.page) {
+                return Some((annotated.image_path.clone(), annotated.page));
+            }
+        }
+        Some((path, image.page))
+    }
+
+    fn get_annotated_image_by_path(
+        &self,
+        image_path: &str,
+        page: Option<usize>,
+    ) -> Option<&AnnotationImage> {
+        self.image_lookup
+            .get(&(image_path.to_string(), page))
+            .and_then(|(stack_index, _)| self.stacks.get(*stack_index))
+            .and_then(|stack| stack.best_index.and_then(|index| stack.images.get(index)))
+    }
+
+    pub fn get_stack_index(&self) -> Option<usize> {
+        self.stack_index
+    }
+
+    pub fn get_pinned_image_index(&self) -> Option<usize> {
+        self.pinned_image_index
+    }
+
+    pub fn is_showing_pinned(&self) -> bool {
+        self.show_pinned
+    }
+
+    pub fn get_pinned_annotation_image(&self) -> Option<AnnotationImage> {
+        self.pinned_image_index.and_then(|image_index| {
+            self.get_current_focus_stack()
+                .and_then(|stack| stack.images.get(image_index).cloned())
+        })
+    }
+
+    // Image that is displayed, which is the pinned one while comparing against it
+    pub fn get_displayed_annotation_image(&self) -> Option<AnnotationImage> {
+        if self.show_pinned {
+            self.get_pinned_annotation_image()
+                .or_else(|| self.get_current_annotation_image())
+        } else {
+            self.get_current_annotation_image()
+        }
+    }
+
+    // Plane one level below the displayed one, used for the difference overlay
+    pub fn get_previous_annotation_image(&self) -> Option<AnnotationImage> {
+        let displayed_index = if self.show_pinned {
+            self.pinned_image_index.or(self.focus_image_index)
+        } else {
+            self.focus_image_index
+        };
+        displayed_index
+            .and_then(|index| index.checked_sub(1))
+            .and_then(|index| {
+                self.get_current_focus_stack()
+                    .and_then(|stack| stack.images.get(index).cloned())
+            })
+    }
+
+    pub fn get_selected_region(&self) -> Option<usize> {
+        self.selected_region
+    }
+
+    fn get_current_focus_stack_mut(&mut self) -> Option<&mut AnnotationZStack> {
+        match self.stack_index {
+            Some(stack_index) => self.stacks.get_mut(stack_index),
+            _ => None,
+        }
+    }
+
+    // Writes the regions of the current stack to the tmp file so they survive a crash
+    fn journal_regions(&mut self) {
+        if let Some(stack_index) = self.stack_index {
+            let stack = &self.stacks[stack_index];
+            self.annotation_cache.push(LightAnnotation {
+                stack_index,
+                focus_image_index: self.focus_image_index.unwrap_or(0),
+                regions: Some(stack.regions.clone()),
+            });
+            self.dirty = true;
+            self.save_tmp();
+        }
+    }
+
+    fn unpin(&mut self) {
+        self.pinned_image_index = None;
+        self.show_pinned = false;
+    }
+
     pub fn get_current_focus_stack(&self) -> Option<&AnnotationZStack> {
         match self.stack_index {
             Some(stack_index) => self.stacks.get(stack_index),
@@ -135,9 +629,20 @@ impl State {
         }
     }
 
+    pub fn get_stacks(&self) -> &[AnnotationZStack] {
+        &self.stacks
+    }
+
     pub fn replace_foucs_stacks(&mut self, mut stacks: Vec<AnnotationZStack>) {
+        self.dirty = false;
+        self.annotation_cache.clear();
+        stacks.iter_mut().for_each(|stack| stack.update_best_z());
+        self.unpin();
+        self.selected_region = None;
+        self.registration_failures.clear();
         self.stacks.clear();
         self.stacks.append(&mut stacks);
+        self.update_image_lookup();
         eprintln!("{}", stacks.len());
 
         if let Some(z_stack) = self.stacks.first() {
@@ -146,14 +651,38 @@ impl State {
                 Some(0)
             } else {
                 None
-            }
+            };
         } else {
             self.stack_index = None;
         }
     }
+
+    fn update_image_lookup(&mut self) {
+        self.image_lookup = self
+            .stacks
+            .iter()
+            .enumerate()
+            .flat_map(|(stack_index, stack)| {
+                stack
+                    .images
+                    .iter()
+                    .enumerate()
+                    .map(move |(image_index, image)| {
+                        (
+                            (image.image_path.clone(), image.page),
+                            (stack_index, image_index),
+                        )
+                    })
+            })
+            .collect();
+    }
     pub fn get_current_foucs_stack_max(&self) -> Option<usize> {
         self.get_current_focus_stack().map(|x| x.images.len() - 1)
     }
+    pub fn get_current_z(&self) -> Option<f64> {
+        self.get_current_annotation_image()
+            .and_then(|annotation_image| annotation_image.get_z())
+    }
     pub fn get_current_foucs_stack_best_index(&self) -> Option<usize> {
         match self.get_current_focus_stack() {
             Some(stack) => stack.best_index,
@@ -161,7 +690,43 @@ impl State {
         }
     }
 
+    // Number of annotated focus stacks and the number of all focus stacks
+    pub fn get_progress(&self) -> (usize, usize) {
+        let annotated = self
+            .stacks
+            .iter()
+            .filter(|x| x.best_index.is_some())
+            .count();
+        (annotated, self.stacks.len())
+    }
+
+    // Moves to the focus level the initial focus strategy selects for the current stack
+    fn apply_initial_focus(&mut self) {
+        let (max, best_index) = match self.get_current_focus_stack() {
+            Some(stack) if !stack.images.is_empty() => (stack.images.len() - 1, stack.best_index),
+            _ => return,
+        };
+        let index = match (self.initial_focus, best_index) {
+            (InitialFocus::Keep, _) => self.focus_image_index.unwrap_or(0),
+            (_, Some(best_index)) => best_index,
+            (InitialFocus::First, None) => 0,
+            (InitialFocus::Middle, None) => max / 2,
+            (InitialFocus::Last, None) => max,
+            (InitialFocus::Sharpest, None) => max / 2,
+        };
+        self.focus_image_index = Some(index.min(max));
+        self.sharpest_focus_pending =
+            self.initial_focus == InitialFocus::Sharpest && best_index.is_none();
+    }
+
+    // The sharpest level is looked up by the UI, which caches the sharpness of the planes
+    pub fn is_sharpest_focus_pending(&self) -> bool {
+        self.sharpest_focus_pending
+    }
+
     pub fn skip(&mut self) {
+        self.unpin();
+        self.selected_region = None;
         let len = self.stacks.len();
         if len == 0 {
             self.stack_index = None;
@@ -177,6 +742,7 @@ impl State {
                     break;
                 }
             }
+            self.apply_initial_focus();
         }
 
         eprintln!("{:?}", self.stack_index)
@@ -186,7 +752,7 @@ impl State {
         match (self.stack_index, self.focus_image_index) {
             (Some(stack_index), Some(_)) => {
                 let best_index = self.focus_image_index;
-                self.stacks[stack_index].best_index = best_index;
+                self.stacks[stack_index].set_best_index(best_index);
                 if let Some(best_index) = best_index {
                     self.annotation_cache
                         .push(LightAnnotation::new(stack_index, best_index))
@@ -195,31 +761,45 @@ impl State {
             (_, _) => {}
         }
     }
-    pub fn integrate_tmp_file(&mut self) {
-        self.get_file_path().map(|mut path| {
-            path.set_extension(ANNOTATION_CACHE_FILE_ENDING);
-
-            if path.exists() {
-                let contents =
-                    fs::read_to_string(path).expect("Something went wrong reading the file");
+    // Annotations journaled for the dataset saved to `path`
+    fn read_journal(path: &Path) -> Result<Vec<LightAnnotation>, String> {
+        let mut path = path.to_path_buf();
+        path.set_extension(ANNOTATION_CACHE_FILE_ENDING);
+        if !path.exists() {
+            eprintln!("Tmp annotation file {:?} does not exist", path);
+            return Ok(Vec::new());
+        }
+        fs::read_to_string(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|contents| serde_json::from_str(&contents).map_err(|e| e.to_string()))
+            .map_err(|e| format!("journal {}: {}", path.display(), e))
+    }
 
-                self.annotation_cache = serde_json::from_str(&contents).unwrap();
-                self.integrate_annotation_cache();
-            } else {
-                eprintln!("Tmp annotation file {:?} does not exist", path);
-            }
-        });
+    fn integrate_journal(&mut self, journal: Vec<LightAnnotation>) {
+        self.annotation_cache = journal;
+        self.integrate_annotation_cache();
+        // The replayed annotations are not in the dataset file yet
+        self.dirty = !self.annotation_cache.is_empty();
     }
     fn integrate_annotation_cache(&mut self) {
         self.annotation_cache.iter().for_each(|annotation| {
-            self.stacks.get_mut(annotation.stack_index).map(|x| {
-                x.best_index = Some(annotation.focus_image_index);
-            });
+            self.stacks
+                .get_mut(annotation.stack_index)
+                .map(|x| match &annotation.regions {
+                    Some(regions) => {
+                        x.regions = regions.clone();
+                        x.update_best_z();
+                    }
+                    None => x.set_best_index(Some(annotation.focus_image_index)),
+                });
         });
     }
 
     pub fn delete_tmp_file(&mut self) {
-        self.get_file_path().map(|mut path| {
+        if self.locked_by.is_some() {
+            return;
+        }
+        self.get_save_path().map(|mut path| {
             path.set_extension(ANNOTATION_CACHE_FILE_ENDING);
             if path.exists() {
                 fs::remove_file(path).unwrap();
@@ -227,99 +807,392 @@ impl State {
         });
     }
 
-    pub fn open(&mut self, file: &File) {
-        let filename = file.path().expect("Couldn't get file path");
+    // The dataset is read before the opened one is closed, a file that cannot be read leaves the
+    // state as it was
+    pub fn open(&mut self, file: &File) -> Result<(), String> {
+        let path = file.path().ok_or("not a local file")?;
+        // An output file written earlier is opened in place of the dataset
+        let (path, output_path) = match self.get_output_path(&path) {
+            Some(output_path) if output_path.exists() => (output_path, None),
+            output_path => (path, output_path),
+        };
+        let stacks = State::read_dataset(&path)?;
+        // The journal of a locked dataset belongs to the session holding the lock
+        let save_path = output_path.clone().unwrap_or_else(|| path.clone());
+        let journal = match State::lock_holder(&save_path) {
+            Some(_) => Vec::new(),
+            None => State::read_journal(&save_path)?,
+        };
+
+        if self.has_file() {
+            // Save before opening a new file, the journal is kept if saving failed
+            self.save();
+            if !self.dirty {
+                self.delete_tmp_file();
+            }
+        }
+        self.release_lock();
+
+        self.replace_foucs_stacks(stacks);
+        self.set_file_path(&path);
+        self.output_path = output_path;
+        self.apply_initial_focus();
+        eprintln!("{:?}", path);
+
+        self.conflict = false;
+        self.disk_version = self.get_save_path().and_then(|x| FileVersion::read(&x));
+        self.locked_by = self.acquire_lock(false).err();
+        if self.locked_by.is_none() {
+            self.integrate_journal(journal);
+            self.delete_tmp_file();
+        }
+        Ok(())
+    }
+
+    fn read_dataset(path: &Path) -> Result<Vec<AnnotationZStack>, String> {
         let now = Instant::now();
         let contents =
-            fs::read_to_string(filename.clone()).expect("Something went wrong reading the file");
+            fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
         let elapsed = now.elapsed();
         println!("Loading file: {:.2?}", elapsed);
 
         let now = Instant::now();
-        let new_dataset: Vec<AnnotationZStack> = serde_json::from_str(&contents).unwrap();
+        let stacks =
+            serde_json::from_str(&contents).map_err(|e| format!("{}: {}", path.display(), e))?;
         let elapsed = now.elapsed();
         println!("Deserialisation file: {:.2?}", elapsed);
+        Ok(stacks)
+    }
 
-        self.replace_foucs_stacks(new_dataset);
-        self.file_name = filename.clone().as_path().file_name().map(|x| {
-            x.to_str()
-                .expect("failed to convert filname to str")
-                .to_string()
-        });
-        self.root_path = filename.clone().as_path().parent().map(|x| {
-            x.to_str()
-                .expect("failed to convert filname to str")
-                .to_string()
-        });
+    // The content is written next to the file first and then moved over it, so a save that is
+    // interrupted never leaves a truncated file behind
+    pub fn save_file<T: Serialize>(path: PathBuf, content: &T) -> Result<(), String> {
+        let now = Instant::now();
+        let contents = serde_json::to_string(content).map_err(|e| e.to_string())?;
+        let elapsed = now.elapsed();
+        println!("Serialization: {:.2?}", elapsed);
 
+        let now = Instant::now();
+        let mut partial_path = path.clone().into_os_string();
+        partial_path.push(PARTIAL_FILE_SUFFIX);
+        fs::write(&partial_path, contents)
+            .and_then(|_| fs::rename(&partial_path, &path))
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        let elapsed = now.elapsed();
+        println!("Writing to file: {:.2?}", elapsed);
+        Ok(())
+    }
+    pub fn get_file_path(&self) -> Option<PathBuf> {
         match (self.root_path.clone(), self.file_name.clone()) {
             (Some(root_path), Some(file_name)) => {
-                let path = Path::new(&root_path).join(Path::new(&file_name));
-                eprintln!("{:?}", path);
+                Some(Path::new(&root_path).join(Path::new(&file_name)))
             }
             (_, _) => {
-                eprintln!("Path not properly set");
+                eprintln!("No save path specified");
+                None
             }
         }
     }
 
-    fn save_file<T: Serialize>(path: PathBuf, content: &T) {
-        match fs::File::create(path) {
-            Ok(mut file) => {
-                let now = Instant::now();
-                let contents = serde_json::to_string(content).expect("Could not serialize.");
-                let elapsed = now.elapsed();
-                println!("Serialization: {:.2?}", elapsed);
+    pub fn save_tmp(&self) {
+        if self.locked_by.is_some() {
+            return;
+        }
+        self.get_save_path().map(|mut path| {
+            path.set_extension(ANNOTATION_CACHE_FILE_ENDING);
+            if let Err(e) = State::save_file(path, &self.annotation_cache) {
+                eprintln!("an error occured while saving annotation cache: {}", e);
+            }
+        });
+    }
+
+    // File the dataset and its journal are written to
+    pub fn get_save_path(&self) -> Option<PathBuf> {
+        self.output_path.clone().or_else(|| self.get_file_path())
+    }
+
+    // In separate output mode the annotations of `dataset` go to `<name>_<user>.json`, next to the
+    // dataset or in the output folder. Not set for a file that is already an output file.
+    fn get_output_path(&self, dataset: &Path) -> Option<PathBuf> {
+        if !self.separate_output {
+            return None;
+        }
+        let stem = dataset.file_stem()?.to_str()?;
+        let suffix = format!(
+            "{}{}",
+            OUTPUT_FILE_SEPARATOR,
+            gtk::glib::user_name().to_string_lossy()
+        );
+        if stem.ends_with(&suffix) {
+            return None;
+        }
+        let dir = match &self.output_dir {
+            Some(output_dir) => output_dir.clone(),
+            None => dataset.parent()?.to_path_buf(),
+        };
+        Some(dir.join(format!("{}{}.json", stem, suffix)))
+    }
 
-                let now = Instant::now();
-                match file.write(contents.as_bytes()) {
-                    Ok(_) => {}
-                    Err(e) => {
-                        eprintln!(
-                            "an error occured while saving annotation cache: {}",
-                            e.to_string()
-                        );
-                    }
+    pub fn save(&mut self) {
+        if let Some(path) = self.get_save_path() {
+            if let Some(holder) = self.lock_taken_over() {
+                eprintln!("{:?} was taken over by {}", path, holder.describe());
+                self.locked_by = Some(holder);
+            }
+            if self.locked_by.is_some() {
+                eprintln!(
+                    "{:?} is opened read-only, it can be saved as another file",
+                    path
+                );
+                return;
+            }
+            if self.changed_on_disk(&path) {
+                // Without changes of our own the other version is kept as it is
+                if self.dirty {
+                    eprintln!(
+                        "{:?} was changed by another program, it is not overwritten",
+                        path
+                    );
+                    self.conflict = true;
                 }
-                let elapsed = now.elapsed();
-                println!("Writing to file: {:.2?}", elapsed);
+                return;
             }
+            self.save_to(path);
+        }
+    }
+
+    fn changed_on_disk(&self, path: &Path) -> bool {
+        match (&self.disk_version, path.exists()) {
+            (_, false) => false,
+            (None, true) => true,
+            (Some(version), true) => !version.matches(path),
+        }
+    }
+
+    // Whether the save file was changed by another program while there were unsaved changes
+    pub fn has_conflict(&self) -> bool {
+        self.conflict
+    }
+
+    // Replaces the dataset with the save file on disk. When merging, the annotations made in this
+    // session are replayed on top of it and the result is saved.
+    fn reload(&mut self, merge: bool) {
+        let path = match self.get_save_path() {
+            Some(path) => path,
+            None => return,
+        };
+        let stacks = match State::read_dataset(&path) {
+            Ok(stacks) => stacks,
             Err(e) => {
-                eprintln!("{}", e.to_string());
+                eprintln!("Could not read the dataset: {}", e);
+                return;
             }
         };
+        let same_stacks = stacks.len() == self.stacks.len()
+            && stacks.iter().zip(self.stacks.iter()).all(|(a, b)| {
+                a.images.len() == b.images.len()
+                    && a.images.iter().zip(b.images.iter()).all(|(a, b)| {
+                        Path::new(&a.image_path).file_name() == Path::new(&b.image_path).file_name()
+                    })
+            });
+        if merge && !same_stacks {
+            eprintln!(
+                "{:?} has different focus stacks, the annotations cannot be merged",
+                path
+            );
+            return;
+        }
+
+        let annotation_cache = std::mem::take(&mut self.annotation_cache);
+        let stack_index = self.stack_index;
+        self.delete_tmp_file();
+        self.replace_foucs_stacks(stacks);
+        self.set_file_path(&path);
+        self.output_path = None;
+        self.conflict = false;
+        self.disk_version = FileVersion::read(&path);
+        if let Some(stack_index) = stack_index.filter(|x| *x < self.stacks.len()) {
+            self.stack_index = Some(stack_index);
+        }
+        self.apply_initial_focus();
+
+        if merge {
+            self.annotation_cache = annotation_cache;
+            self.integrate_annotation_cache();
+            self.save_tmp();
+            self.dirty = true;
+            self.save();
+        }
+    }
+
+    fn get_lock_path(&self) -> Option<PathBuf> {
+        self.get_save_path().map(|path| State::lock_path_of(&path))
+    }
+
+    fn lock_path_of(path: &Path) -> PathBuf {
+        let mut lock_path = path.as_os_str().to_os_string();
+        lock_path.push(LOCK_FILE_SUFFIX);
+        PathBuf::from(lock_path)
+    }
+
+    // Another session that is running and holds the lock of the dataset at `path`
+    fn lock_holder(path: &Path) -> Option<LockInfo> {
+        LockInfo::read(&State::lock_path_of(path))
+            .filter(|holder| *holder != LockInfo::current() && !holder.is_stale())
+    }
+
+    // Creates the lock file of the save file, fails with the session that holds it unless the lock
+    // is forced. Folders without write access are opened without a lock.
+    fn acquire_lock(&mut self, force: bool) -> Result<(), LockInfo> {
+        let path = match self.get_lock_path() {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let own = LockInfo::current();
+        let contents = serde_json::to_string(&own).unwrap();
+        let created = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .and_then(|mut file| file.write_all(contents.as_bytes()));
+        let result = match created {
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => match LockInfo::read(&path) {
+                Some(holder) if !force && holder != own && !holder.is_stale() => {
+                    return Err(holder)
+                }
+                _ => fs::write(&path, contents),
+            },
+            result => result,
+        };
+        match result {
+            Ok(_) => self.lock_path = Some(path),
+            Err(e) => eprintln!("Could not lock the dataset with {:?}: {}", path, e),
+        }
+        Ok(())
     }
-    fn get_file_path(&self) -> Option<PathBuf> {
-        match (self.root_path.clone(), self.file_name.clone()) {
-            (Some(root_path), Some(file_name)) => {
-                Some(Path::new(&root_path).join(Path::new(&file_name)))
-            }
-            (_, _) => {
-                eprintln!("No save path specified");
-                None
+
+    fn release_lock(&mut self) {
+        if let Some(path) = self.lock_path.take() {
+            // The lock of a session that took over the dataset is left in place
+            if LockInfo::read(&path) == Some(LockInfo::current()) {
+                if let Err(e) = fs::remove_file(&path) {
+                    eprintln!("Could not remove the lock {:?}: {}", path, e);
+                }
             }
         }
+        self.locked_by = None;
     }
 
-    pub fn save_tmp(&self) {
-        self.get_file_path().map(|mut path| {
-            path.set_extension(ANNOTATION_CACHE_FILE_ENDING);
-            State::save_file(path, &self.annotation_cache);
-        });
+    // Another session that took the lock of the dataset while it was open here
+    fn lock_taken_over(&self) -> Option<LockInfo> {
+        let holder = LockInfo::read(self.lock_path.as_ref()?)?;
+        match holder == LockInfo::current() {
+            true => None,
+            false => Some(holder),
+        }
     }
 
-    pub fn save(&self) {
-        self.get_file_path().map(|path| {
-            State::save_file(path, &self.stacks);
-        });
+    // Session holding the lock of a dataset that was opened read-only
+    pub fn get_lock_holder(&self) -> Option<&LockInfo> {
+        self.locked_by.as_ref()
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.locked_by.is_some()
+    }
+
+    // The annotations journaled by the other session are replayed before the ones made here
+    fn take_over_lock(&mut self) {
+        let path = match (&self.locked_by, self.get_save_path()) {
+            (Some(_), Some(path)) => path,
+            (_, _) => return,
+        };
+        let mut journal = match State::read_journal(&path) {
+            Ok(journal) => journal,
+            Err(e) => return self.set_error(format!("Could not take over the dataset: {}", e)),
+        };
+        if self.acquire_lock(true).is_err() {
+            return;
+        }
+        self.locked_by = None;
+        journal.append(&mut self.annotation_cache);
+        self.annotation_cache = journal;
+        self.reload(true);
+    }
+
+    fn set_file_path(&mut self, path: &Path) {
+        self.file_name = path.file_name().map(|x| x.to_string_lossy().to_string());
+        self.root_path = path.parent().map(|x| x.to_string_lossy().to_string());
+    }
+
+    // Image paths are relative to the dataset file, they are rebased when it is written to another
+    // folder. The dataset continues in the written file if that is not the opened one.
+    fn save_to(&mut self, path: PathBuf) {
+        let (root_path, file_path) = match (self.root_path.clone(), self.get_file_path()) {
+            (Some(root_path), Some(file_path)) => (PathBuf::from(root_path), file_path),
+            (_, _) => return,
+        };
+        let result = path
+            .parent()
+            .ok_or_else(|| format!("{} has no parent folder", path.display()))
+            .and_then(|dir| {
+                fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+                relative_path(dir, &root_path)
+            })
+            .and_then(|prefix| {
+                if prefix.as_os_str().is_empty() {
+                    State::save_file(path.clone(), &self.stacks)?;
+                } else {
+                    let mut stacks = self.stacks.clone();
+                    stacks.iter_mut().for_each(|stack| stack.rebase(&prefix));
+                    State::save_file(path.clone(), &stacks)?;
+                }
+                Ok(prefix)
+            });
+        let prefix = match result {
+            Ok(prefix) => prefix,
+            Err(e) => {
+                eprintln!("an error occured while saving the dataset: {}", e);
+                return;
+            }
+        };
+        self.dirty = false;
+        self.conflict = false;
+        self.disk_version = FileVersion::read(&path);
+        // The journal only keeps changes that are not saved yet, a merge replays nothing else
+        self.delete_tmp_file();
+        self.annotation_cache.clear();
+
+        if path != file_path || self.output_path.is_some() {
+            // The lock belongs to the previous save path
+            self.release_lock();
+            if !prefix.as_os_str().is_empty() {
+                self.stacks
+                    .iter_mut()
+                    .for_each(|stack| stack.rebase(&prefix));
+                self.update_image_lookup();
+            }
+            self.set_file_path(&path);
+            self.output_path = None;
+            self.locked_by = self.acquire_lock(false).err();
+            eprintln!("Continuing in {:?}", path);
+        }
+    }
+
+    // Whether the dataset has changes that are only in the journal
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
     }
 
     pub fn previous(&mut self) {
+        self.unpin();
+        self.selected_region = None;
         let len = self.stacks.len();
         if len == 0 {
             self.stack_index = None;
         } else if self.stack_index.map_or_else(|| false, |x| x > 0) {
-            self.stack_index = self.stack_index.map(|x| x - 1)
+            self.stack_index = self.stack_index.map(|x| x - 1);
+            self.apply_initial_focus();
         }
     }
 }
@@ -329,14 +1202,256 @@ pub struct AnnotationZStack {
     pub images: Vec<AnnotationImage>,
     pub best_index: Option<usize>,
 
+    // Physical z the offsets are measured against, defaults to the first plane
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reference_z: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub best_z: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub best_z_offset: Option<f64>,
+    // Pixels shared by neighbouring tiles
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tile_overlap: Option<u32>,
+    // Parts of the tile that are in focus at a different plane than `best_index`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub regions: Vec<Region>,
+
+    #[serde(flatten)]
+    extra: HashMap<String, Value>,
+}
+
+impl AnnotationZStack {
+    // Puts `prefix` in front of every relative image path
+    pub fn rebase(&mut self, prefix: &Path) {
+        self.images.iter_mut().for_each(|image| {
+            image.image_path = rebase_path(prefix, &image.image_path);
+            image.neighbours.map_paths(|path| rebase_path(prefix, path));
+        });
+    }
+
+    pub fn new(images: Vec<AnnotationImage>) -> Self {
+        let mut stack = AnnotationZStack {
+            images,
+            best_index: None,
+            reference_z: None,
+            best_z: None,
+            best_z_offset: None,
+            tile_overlap: None,
+            regions: Vec::new(),
+            extra: HashMap::new(),
+        };
+        stack.update_best_z();
+        stack
+    }
+
+    pub fn set_best_index(&mut self, best_index: Option<usize>) {
+        self.best_index = best_index;
+        self.update_best_z();
+    }
+
+    pub fn update_best_z(&mut self) {
+        self.best_z = self
+            .best_index
+            .and_then(|index| self.images.get(index))
+            .and_then(|image| image.get_z());
+        self.best_z_offset = self.get_z_offset(self.best_z);
+
+        let images = &self.images;
+        self.regions.iter_mut().for_each(|region| {
+            region.best_z = region
+                .best_index
+                .and_then(|index| images.get(index))
+                .and_then(|image| image.get_z());
+        });
+    }
+
+    pub fn get_reference_z(&self) -> Option<f64> {
+        self.reference_z
+            .or_else(|| self.images.first().and_then(|image| image.get_z()))
+    }
+
+    pub fn get_z_offset(&self, z: Option<f64>) -> Option<f64> {
+        match (z, self.get_reference_z()) {
+            (Some(z), Some(reference_z)) => Some(z - reference_z),
+            (_, _) => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Region {
+    // Rectangle relative to the tile size, 0.0 to 1.0
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+    pub best_index: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub best_z: Option<f64>,
+
+    #[serde(flatten)]
+    extra: HashMap<String, Value>,
+}
+
+impl Region {
+    pub fn new(x: f64, y: f64, width: f64, height: f64) -> Self {
+        Region {
+            x,
+            y,
+            width,
+            height,
+            best_index: None,
+            best_z: None,
+            extra: HashMap::new(),
+        }
+    }
+
+    pub fn contains(&self, x: f64, y: f64) -> bool {
+        x >= self.x && x <= self.x + self.width && y >= self.y && y <= self.y + self.height
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NeighbourOffset {
+    pub dx: i32,
+    pub dy: i32,
+    pub path: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged, try_from = "UncheckedNeighbours")]
+pub enum Neighbours {
+    // Row-major square around the image without the image itself, 8 entries for 3x3, 24 for 5x5, ...
+    Square(Vec<Option<String>>),
+    Offsets(Vec<NeighbourOffset>),
+}
+
+// Neighbours as they are read, a square list must have (2r+1)²-1 entries
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum UncheckedNeighbours {
+    Square(Vec<Option<String>>),
+    Offsets(Vec<NeighbourOffset>),
+}
+
+impl TryFrom<UncheckedNeighbours> for Neighbours {
+    type Error = String;
+
+    fn try_from(neighbours: UncheckedNeighbours) -> Result<Self, Self::Error> {
+        match neighbours {
+            UncheckedNeighbours::Square(paths) => {
+                let neighbours = Neighbours::Square(paths);
+                let side = 2 * neighbours.radius() + 1;
+                match neighbours {
+                    Neighbours::Square(paths) if paths.len() != side * side - 1 => Err(format!(
+                        "{} neighbours do not form a square around the image, expected 8, 24, 48, …",
+                        paths.len()
+                    )),
+                    neighbours => Ok(neighbours),
+                }
+            }
+            UncheckedNeighbours::Offsets(offsets) => Ok(Neighbours::Offsets(offsets)),
+        }
+    }
+}
+
+impl Neighbours {
+    // Offsets (dx, dy) of a square neighbourhood in the order they are stored in `Neighbours::Square`
+    pub fn square_offsets(radius: usize) -> Vec<(i32, i32)> {
+        let radius = radius as i32;
+        (-radius..=radius)
+            .flat_map(|dy| (-radius..=radius).map(move |dx| (dx, dy)))
+            .filter(|offset| *offset != (0, 0))
+            .collect()
+    }
+
+    pub fn radius(&self) -> usize {
+        match self {
+            Neighbours::Square(paths) => {
+                let mut radius = 0;
+                while (2 * radius + 1) * (2 * radius + 1) - 1 < paths.len() {
+                    radius += 1;
+                }
+                radius
+            }
+            Neighbours::Offsets(offsets) => offsets
+                .iter()
+                .map(|x| x.dx.unsigned_abs().max(x.dy.unsigned_abs()) as usize)
+                .max()
+                .unwrap_or(0),
+        }
+    }
+
+    pub fn get(&self, dx: i32, dy: i32) -> Option<&String> {
+        match self {
+            Neighbours::Square(paths) => Neighbours::square_offsets(self.radius())
+                .iter()
+                .position(|x| *x == (dx, dy))
+                .and_then(|index| paths.get(index))
+                .and_then(|x| x.as_ref()),
+            Neighbours::Offsets(offsets) => offsets
+                .iter()
+                .find(|x| x.dx == dx && x.dy == dy)
+                .and_then(|x| x.path.as_ref()),
+        }
+    }
+
+    pub fn map_paths<F: Fn(&str) -> String>(&mut self, f: F) {
+        match self {
+            Neighbours::Square(paths) => {
+                paths.iter_mut().flatten().for_each(|path| *path = f(path))
+            }
+            Neighbours::Offsets(offsets) => offsets
+                .iter_mut()
+                .filter_map(|x| x.path.as_mut())
+                .for_each(|path| *path = f(path)),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = ((i32, i32), Option<&String>)> + '_ {
+        let offsets: Vec<(i32, i32)> = match self {
+            Neighbours::Square(_) => Neighbours::square_offsets(self.radius()),
+            Neighbours::Offsets(offsets) => offsets.iter().map(|x| (x.dx, x.dy)).collect(),
+        };
+        offsets
+            .into_iter()
+            .map(move |(dx, dy)| ((dx, dy), self.get(dx, dy)))
+    }
+}
+
+// Shift in pixels that aligns a plane with the reference plane of its stack
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Transform {
+    pub dx: f64,
+    pub dy: f64,
+
     #[serde(flatten)]
     extra: HashMap<String, Value>,
 }
 
+impl Transform {
+    pub fn translation(dx: f64, dy: f64) -> Self {
+        Transform {
+            dx,
+            dy,
+            extra: HashMap::new(),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AnnotationImage {
     pub image_path: String,
-    pub neighbours: [Option<String>; 8],
+    pub neighbours: Neighbours,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub z: Option<f64>,
+    // Page of a multi-page TIFF, used for the image and its neighbours
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub page: Option<usize>,
+    // Drift of the plane relative to the reference plane of the stack
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transform: Option<Transform>,
 
     #[serde(flatten)]
     extra: HashMap<String, Value>,
@@ -344,15 +1459,242 @@ pub struct AnnotationImage {
 
 impl AnnotationImage {
     pub fn from_vec(image_path: String, neighbours: Vec<Option<String>>) -> Self {
-        let mut _neighbours = [NONE_STRING_OPTION; 8];
-        for (index, element) in (0..8).zip(neighbours.iter()) {
-            _neighbours[index] = element.clone();
-        }
-
         AnnotationImage {
             image_path,
-            neighbours: _neighbours,
+            neighbours: Neighbours::Square(neighbours),
+            z: None,
+            page: None,
+            transform: None,
             extra: HashMap::new(),
         }
     }
+
+    // Physical z position, either stored explicitly or taken from the `_Z4648` part of the file
+    // name. The pages of a multi-page file share its name, so their z has to be stored.
+    pub fn get_z(&self) -> Option<f64> {
+        match self.page {
+            Some(_) => self.z,
+            None => self
+                .z
+                .or_else(|| AnnotationImage::parse_z(&self.image_path)),
+        }
+    }
+
+    fn parse_z(image_path: &str) -> Option<f64> {
+        let file_stem = Path::new(image_path).file_stem()?.to_str()?;
+        file_stem.split('_').rev().find_map(|part| {
+            part.strip_prefix(Z_FILE_NAME_PREFIX)
+                .and_then(|z| z.parse::<f64>().ok())
+        })
+    }
+}
+
+// Session that opened a dataset, stored in its lock file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LockInfo {
+    pub user: String,
+    pub host: String,
+    pub pid: u32,
+}
+
+impl LockInfo {
+    fn current() -> Self {
+        LockInfo {
+            user: gtk::glib::user_name().to_string_lossy().to_string(),
+            host: gtk::glib::host_name().to_string(),
+            pid: process::id(),
+        }
+    }
+
+    fn read(path: &Path) -> Option<LockInfo> {
+        let contents = fs::read_to_string(path).ok()?;
+        serde_json::from_str(&contents).ok()
+    }
+
+    // Left behind by a session on this computer that is no longer running
+    fn is_stale(&self) -> bool {
+        self.host == LockInfo::current().host && !LockInfo::is_running(self.pid)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn is_running(pid: u32) -> bool {
+        Path::new("/proc").join(pid.to_string()).exists()
+    }
+
+    // Without a way to tell, the session is assumed to be running
+    #[cfg(not(target_os = "linux"))]
+    fn is_running(_pid: u32) -> bool {
+        true
+    }
+
+    pub fn describe(&self) -> String {
+        format!("{} on {} (process {})", self.user, self.host, self.pid)
+    }
+}
+
+// Size, modification time and content hash of a file
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct FileVersion {
+    len: u64,
+    modified: Option<SystemTime>,
+    hash: u64,
+}
+
+impl FileVersion {
+    fn read(path: &Path) -> Option<FileVersion> {
+        let metadata = fs::metadata(path).ok()?;
+        let contents = fs::read(path).ok()?;
+        Some(FileVersion {
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+            hash: FileVersion::hash(&contents),
+        })
+    }
+
+    fn hash(contents: &[u8]) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        contents.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    // The content is only compared when the modification time or size differ, e.g. a sync tool
+    // may touch a file without changing it
+    fn matches(&self, path: &Path) -> bool {
+        let metadata = match fs::metadata(path) {
+            Ok(metadata) => metadata,
+            Err(_) => return false,
+        };
+        if metadata.len() == self.len && metadata.modified().ok() == self.modified {
+            return true;
+        }
+        fs::read(path).map_or(false, |contents| FileVersion::hash(&contents) == self.hash)
+    }
+}
+
+// `prefix` joined with `path`, a folder followed by `..` is left out
+fn rebase_path(prefix: &Path, path: &str) -> String {
+    let joined = prefix.join(path);
+    let mut rebased: Vec<Component> = Vec::new();
+    for component in joined.components() {
+        match (component, rebased.last()) {
+            (Component::ParentDir, Some(Component::Normal(_))) => {
+                rebased.pop();
+            }
+            (component, _) => rebased.push(component),
+        }
+    }
+    rebased
+        .iter()
+        .collect::<PathBuf>()
+        .to_string_lossy()
+        .to_string()
+}
+
+// Path that leads from the folder `from` to the folder `to`
+fn relative_path(from: &Path, to: &Path) -> Result<PathBuf, String> {
+    let from = from
+        .canonicalize()
+        .map_err(|e| format!("{}: {}", from.display(), e))?;
+    let to = to
+        .canonicalize()
+        .map_err(|e| format!("{}: {}", to.display(), e))?;
+    let common = from
+        .components()
+        .zip(to.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let mut path = PathBuf::new();
+    from.components().skip(common).for_each(|_| path.push(".."));
+    to.components()
+        .skip(common)
+        .for_each(|component| path.push(component));
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Focus stack from the example in the README
+    const README_STACK: &str = r#"{
+        "images": [
+            {
+                "image_path": "img/31/I03987/I03987_X008_Y026_Z5498_0_1200.jpg",
+                "neighbours": [
+                    null,
+                    null,
+                    null,
+                    "img/31/I03987/I03987_X008_Y026_Z5498_0_1125.jpg",
+                    null,
+                    "img/31/I03987/I03987_X008_Y026_Z5498_75_1125.jpg",
+                    "img/31/I03987/I03987_X008_Y026_Z5498_75_1200.jpg",
+                    null
+                ]
+            }
+        ],
+        "best_index": null
+    }"#;
+
+    #[test]
+    fn eight_neighbours_round_trip() {
+        let value: Value = serde_json::from_str(README_STACK).unwrap();
+        let stack: AnnotationZStack = serde_json::from_value(value.clone()).unwrap();
+
+        let neighbours = &stack.images[0].neighbours;
+        assert!(matches!(neighbours, Neighbours::Square(paths) if paths.len() == 8));
+        assert_eq!(neighbours.radius(), 1);
+        assert_eq!(
+            neighbours.get(-1, 0).map(|x| x.as_str()),
+            Some("img/31/I03987/I03987_X008_Y026_Z5498_0_1125.jpg")
+        );
+        assert_eq!(serde_json::to_value(&stack).unwrap(), value);
+    }
+
+    #[test]
+    fn twenty_four_neighbours_map_to_offsets() {
+        let paths: Vec<Option<String>> = (0..24).map(|x| Some(x.to_string())).collect();
+        let neighbours: Neighbours = serde_json::from_value(serde_json::json!(paths)).unwrap();
+
+        assert_eq!(neighbours.radius(), 2);
+        assert_eq!(neighbours.get(-2, -2).map(|x| x.as_str()), Some("0"));
+        assert_eq!(neighbours.get(2, -2).map(|x| x.as_str()), Some("4"));
+        assert_eq!(neighbours.get(-1, 0).map(|x| x.as_str()), Some("11"));
+        assert_eq!(neighbours.get(1, 0).map(|x| x.as_str()), Some("12"));
+        assert_eq!(neighbours.get(2, 2).map(|x| x.as_str()), Some("23"));
+        assert_eq!(neighbours.get(0, 0), None);
+    }
+
+    #[test]
+    fn z_is_only_parsed_from_single_page_files() {
+        let mut image =
+            AnnotationImage::from_vec("img/I03987_X008_Y026_Z5498.tif".to_string(), vec![None; 8]);
+        assert_eq!(image.get_z(), Some(5498.0));
+
+        image.page = Some(2);
+        assert_eq!(image.get_z(), None);
+        image.z = Some(5566.0);
+        assert_eq!(image.get_z(), Some(5566.0));
+    }
+
+    #[test]
+    fn non_square_neighbours_are_rejected() {
+        let paths: Vec<Option<String>> = vec![None; 10];
+        assert!(serde_json::from_value::<Neighbours>(serde_json::json!(paths)).is_err());
+    }
+
+    #[test]
+    fn offset_neighbours_deserialise() {
+        let neighbours: Neighbours = serde_json::from_str(
+            r#"[{"dx": -3, "dy": 0, "path": "img/left.jpg"}, {"dx": 1, "dy": 1, "path": null}]"#,
+        )
+        .unwrap();
+
+        assert!(matches!(neighbours, Neighbours::Offsets(_)));
+        assert_eq!(neighbours.radius(), 3);
+        assert_eq!(
+            neighbours.get(-3, 0).map(|x| x.as_str()),
+            Some("img/left.jpg")
+        );
+        assert_eq!(neighbours.get(1, 1), None);
+    }
 }
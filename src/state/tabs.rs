@@ -0,0 +1,124 @@
+use gtk::{gio::File, prelude::FileExt};
+
+use super::{Message, State};
+
+// Datasets opened side by side, every tab keeps its own state and annotation journal. Messages
+// are applied to the selected tab, settings of the shared controls to all tabs.
+#[derive(Debug)]
+pub struct Tabs {
+    states: Vec<State>,
+    selected: usize,
+}
+
+impl Tabs {
+    pub fn new() -> Self {
+        Tabs {
+            states: vec![State::new()],
+            selected: 0,
+        }
+    }
+
+    pub fn states(&self) -> &[State] {
+        &self.states
+    }
+
+    pub fn selected(&self) -> usize {
+        self.selected
+    }
+
+    pub fn current(&self) -> &State {
+        &self.states[self.selected]
+    }
+
+    fn current_mut(&mut self) -> &mut State {
+        &mut self.states[self.selected]
+    }
+
+    // Tab that has the file open
+    fn position(&self, file: &File) -> Option<usize> {
+        let path = file.path()?;
+        self.states.iter().position(|state| state.is_open(&path))
+    }
+
+    pub fn update(&mut self, msg: &Message) {
+        // An error is only shown after the message that caused it
+        for state in self.states.iter_mut() {
+            state.error = None;
+        }
+        match msg {
+            Message::OpenFile(file) | Message::OpenFileInTab(file) => {
+                // A dataset is only opened once so two tabs never write the same file
+                if let Some(index) = self.position(file) {
+                    self.selected = index;
+                    return;
+                }
+                let mut previous = None;
+                if let Message::OpenFileInTab(_) = msg {
+                    if self.current().has_file() {
+                        let state = State::with_settings_of(self.current());
+                        self.states.push(state);
+                        previous = Some(self.selected);
+                        self.selected = self.states.len() - 1;
+                    }
+                }
+                if let Err(e) = self.current_mut().open(file) {
+                    // The tab that was added for the dataset is removed again
+                    if let Some(previous) = previous {
+                        self.states.pop();
+                        self.selected = previous;
+                    }
+                    self.current_mut()
+                        .set_error(format!("Could not open the dataset: {}", e));
+                }
+            }
+            Message::SaveAs(path) => {
+                // Both tabs hold the same lock, they would save over each other's annotations
+                let selected = self.selected;
+                let open_elsewhere = self
+                    .states
+                    .iter()
+                    .enumerate()
+                    .any(|(index, state)| index != selected && state.is_open(path));
+                if open_elsewhere {
+                    self.current_mut().set_error(format!(
+                        "{} is open in another tab, it is not saved over",
+                        path.display()
+                    ));
+                } else {
+                    self.current_mut().update(msg);
+                }
+            }
+            Message::SelectTab(index) => {
+                if *index < self.states.len() {
+                    self.selected = *index;
+                }
+            }
+            Message::CloseTab(index) => {
+                if *index >= self.states.len() {
+                    return;
+                }
+                let mut state = self.states.remove(*index);
+                state.update(&Message::Quit);
+                if self.states.is_empty() {
+                    self.states.push(State::with_settings_of(&state));
+                }
+                if self.selected > *index || self.selected >= self.states.len() {
+                    self.selected -= 1;
+                }
+            }
+            Message::SkipMarkedToogled(_)
+            | Message::DisplaySettingsChanged(_)
+            | Message::NeighbourSettingsChanged(_)
+            | Message::OverlayChanged(_)
+            | Message::AlignPlanes(_)
+            | Message::PreferencesChanged(_)
+            | Message::Autosave
+            | Message::Quit => {
+                for state in self.states.iter_mut() {
+                    state.update(msg);
+                }
+            }
+            _ => self.current_mut().update(msg),
+        }
+    }
+}
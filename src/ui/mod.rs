@@ -1,44 +1,157 @@
-use std::{path::Path, sync::Arc};
+use std::{
+    cell::{Cell, RefCell},
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use adw::{Application, ApplicationWindow, HeaderBar, SplitButton};
+use adw::{
+    prelude::{
+        ActionRowExt, ComboRowExt, PreferencesGroupExt, PreferencesPageExt, PreferencesWindowExt,
+    },
+    ActionRow, Application, ApplicationWindow, ColorScheme, ComboRow, HeaderBar, PreferencesGroup,
+    PreferencesPage, PreferencesWindow, SplitButton, StyleManager, TabBar, TabView, WindowTitle,
+};
 use gtk::{
-    glib::Sender,
+    gdk::{Display, Key, ModifierType},
+    gdk_pixbuf::{Colorspace, Pixbuf},
+    gio::{File, Menu, MenuItem},
+    glib::{
+        idle_add_local_once, timeout_add_local, timeout_add_seconds_local, Bytes, Continue,
+        MainContext, Sender, SourceId, ToVariant, PRIORITY_DEFAULT,
+    },
+    prelude::{Cast, DrawingAreaExtManual, ObjectExt, PaintableExt},
     traits::{
-        BoxExt, ButtonExt, DialogExt, FileChooserExt, GridExt, GtkApplicationExt, GtkWindowExt,
-        RangeExt, ScaleExt, ToggleButtonExt, WidgetExt,
+        AdjustmentExt, BoxExt, ButtonExt, CheckButtonExt, DialogExt, EditableExt, FileChooserExt,
+        GridExt, GtkApplicationExt, GtkWindowExt, RangeExt, ScaleExt, ToggleButtonExt, WidgetExt,
     },
-    ActionBar, AspectFrame, Box, Button, CheckButton, FileChooserAction, FileChooserDialog,
-    FileFilter, Grid, Image, Orientation, PositionType, ResponseType, Scale, Separator,
-    ToggleButton,
+    ActionBar, Align, AspectFrame, Box, Button, CheckButton, CssProvider, Dialog, DialogFlags,
+    DrawingArea, DropDown, Entry, FileChooserAction, FileChooserDialog, FileFilter, Grid, Image,
+    Label, MenuButton, Orientation, PolicyType, Popover, PositionType, ProgressBar, ResponseType,
+    Scale, ScrolledWindow, Separator, SpinButton, StringList, StyleContext, Switch, ToggleButton,
+    STYLE_PROVIDER_PRIORITY_APPLICATION,
 };
 
 use crate::{
+    config::{Config, InitialFocus, Preferences, RecentFile, Theme},
     constants::{
-        MARGIN_LEFT, MARGIN_RIGHT_SCALE_ADDITIONAL, MARGIN_TOP, SCALE_STEP, TOGGLE_NEIGHBOURS_TEXT,
+        APPLICATION_CSS, APPLICATION_TITLE, COMPARE_HOLD_KEY, DEFAULT_IMPORT_PATTERN,
+        DEFAULT_TILE_PATTERN, DEFAULT_TILE_SIZE, DIRTY_INDICATOR, EMPTY_TAB_TITLE,
+        FILMSTRIP_BEST_CSS_CLASS, FILMSTRIP_CURRENT_CSS_CLASS, FLICKER_INTERVAL_MS,
+        FOCUS_DIGIT_TIMEOUT_MS, FOCUS_PAGE_STEP, IMAGE_CACHE_SIZE, MARGIN_LEFT,
+        MARGIN_RIGHT_SCALE_ADDITIONAL, MARGIN_TOP, MAX_ZOOM, NEIGHBOURHOOD_SIZES,
+        READ_ONLY_INDICATOR, REGION_COLOUR, REGION_FOCUS_COLOUR, REGION_MIN_SIZE, SCALE_STEP,
+        SHARPNESS_WINDOW, SHORTCUTS, THUMBNAIL_SIZE, TOGGLE_NEIGHBOURS_TEXT,
         TOGGLE_NEIGHBOURS_TEXT_TOGGLED,
     },
-    state::{Message, State, UIMessage},
+    export::{self, ExportFormat, FocusMapOptions, FocusMapReport},
+    imaging::{
+        all_in_focus, mosaic, register_planes, Channel, DisplaySettings, NeighbourSettings,
+        Overlay, Plane, PlaneCache,
+    },
+    import::ImportOptions,
+    state::{FocusJump, Message, Neighbours, Region, State, Tabs, UIMessage},
     AnnotationImage, MARGIN_BOTTOM,
 };
 
+// Events sent by the worker thread that exports focus maps
+enum FocusMapExport {
+    // Number of stacks done and the number of all stacks
+    Progress(usize, usize),
+    Done(Result<FocusMapReport, String>),
+}
+
+// Regions of the current focus stack as they are drawn over the individual image
+#[derive(Debug, Default)]
+pub struct RegionDrawing {
+    regions: Vec<Region>,
+    selected: Option<usize>,
+    focus_index: Option<usize>,
+    // Start point and offset of the rectangle that is currently dragged, in widget coordinates
+    pub drag: Option<(f64, f64, f64, f64)>,
+}
+
 #[derive(Debug, Clone, Builder)]
 pub struct ImageUI {
     pub window: Arc<ApplicationWindow>,
+    pub window_title: Arc<WindowTitle>,
     pub application_vertical_widget: Arc<Box>,
     pub individual: Arc<Image>,
+    // Individual image with the regions of the focus stack drawn on top
+    pub individual_view: Arc<gtk::Overlay>,
+    pub region_area: Arc<DrawingArea>,
+    pub region_drawing: Rc<RefCell<RegionDrawing>>,
     pub center: Arc<Image>,
-    pub neighbours: [Arc<Image>; 8],
+    // Neighbour images of the grid with their offset (dx, dy) to the center
+    pub neighbours: Rc<RefCell<Vec<((i32, i32), Image)>>>,
+    pub grid_radius: Rc<Cell<usize>>,
     pub focus_scale: Arc<Scale>,
     pub focus_neighbours_grid: Arc<Grid>,
     pub focus_neighbours_aspect_frame: Arc<AspectFrame>,
+    pub image_scrolled_window: Arc<ScrolledWindow>,
+    pub center_content: Arc<Box>,
+    pub tab_view: TabView,
+    // Set while the tabs are synchronised with the state, tab signals are ignored meanwhile
+    pub tabs_updating: Rc<Cell<bool>>,
+    pub zoom: Rc<Cell<f64>>,
+    pub mosaic_image: Arc<Image>,
+    pub comparison_box: Arc<Box>,
+    pub pinned_image: Arc<Image>,
+    pub comparison_image: Arc<Image>,
+    pub all_in_focus_box: Arc<Box>,
+    pub all_in_focus_current_image: Arc<Image>,
+    pub all_in_focus_image: Arc<Image>,
+    // Root path and index of the stack the composite was computed for and if it was aligned
+    pub all_in_focus_cache: Rc<RefCell<Option<((String, usize, bool), Rc<Plane>)>>>,
+    pub filmstrip: Arc<Box>,
+    pub filmstrip_sharpness_checkbox: CheckButton,
+    pub filmstrip_buttons: Rc<RefCell<Vec<Button>>>,
+    // Root path and index of the stack the filmstrip currently shows
+    pub filmstrip_stack: Rc<RefCell<Option<(String, usize)>>>,
+    pub sharpness_cache: Rc<RefCell<HashMap<(PathBuf, Option<usize>), f64>>>,
+    // Z index typed so far and when its last digit was typed
+    pub focus_digits: Rc<Cell<(usize, Option<Instant>)>>,
 
     pub neighbour_toggle_button: ToggleButton,
+    pub mosaic_checkbox: CheckButton,
+    pub outline_centre_checkbox: CheckButton,
+    pub own_focus_checkbox: CheckButton,
+    pub neighbourhood_dropdown: DropDown,
+    pub pin_button: Arc<Button>,
+    pub mark_region_button: Arc<Button>,
+    pub remove_region_button: Arc<Button>,
+    pub split_view_toggle_button: ToggleButton,
+    pub flicker_toggle_button: ToggleButton,
+    pub all_in_focus_toggle_button: ToggleButton,
+    pub flicker_source: Rc<RefCell<Option<SourceId>>>,
+    pub autosave_source: Rc<RefCell<Option<SourceId>>>,
+    // Set while focus maps are exported on a worker thread
+    pub exporting_focus_maps: Rc<Cell<bool>>,
+    pub applied_preferences: Rc<RefCell<Option<Preferences>>>,
+    // Save files whose conflict was shown, autosave does not show it again
+    pub notified_conflicts: Rc<RefCell<HashSet<PathBuf>>>,
     pub skip_marked_checkbox: CheckButton,
     pub open_button: Arc<SplitButton>,
+    pub recent_menu: Menu,
+    // Written to the config when a dataset is opened, saved or closed, not on every annotation
+    pub recent_files: Rc<RefCell<Vec<RecentFile>>>,
+    pub brightness_scale: Scale,
+    pub contrast_scale: Scale,
+    pub gamma_scale: Scale,
+    pub auto_levels_checkbox: CheckButton,
+    pub channel_dropdown: DropDown,
+    pub overlay_dropdown: DropDown,
+    pub align_planes_checkbox: CheckButton,
+    pub reset_display_button: Button,
     pub back_button: Arc<Button>,
     pub skip_button: Arc<Button>,
     pub focus_button: Arc<Button>,
     pub sender: Sender<Message>,
+    pub plane_cache: Rc<RefCell<PlaneCache>>,
+    // Set while the drift of the shown stack is estimated, until its `Message::PlaneShifts` arrives
+    pub registering: Rc<Cell<bool>>,
 }
 
 impl ImageUI {
@@ -59,41 +172,160 @@ impl ImageUI {
         builder
             .application_vertical_widget(application_vertical_widget.clone())
             .window(window)
-            .sender(sender);
+            .sender(sender)
+            .flicker_source(Rc::new(RefCell::new(None)))
+            .autosave_source(Rc::new(RefCell::new(None)))
+            .exporting_focus_maps(Rc::new(Cell::new(false)))
+            .applied_preferences(Rc::new(RefCell::new(None)))
+            .notified_conflicts(Rc::new(RefCell::new(HashSet::new())))
+            .filmstrip_buttons(Rc::new(RefCell::new(Vec::new())))
+            .filmstrip_stack(Rc::new(RefCell::new(None)))
+            .sharpness_cache(Rc::new(RefCell::new(HashMap::new())))
+            .focus_digits(Rc::new(Cell::new((0, None))))
+            .plane_cache(Rc::new(RefCell::new(PlaneCache::new(IMAGE_CACHE_SIZE))))
+            .registering(Rc::new(Cell::new(false)));
         // TODO: move into builder
         ImageUI::build_header(&mut builder, application_vertical_widget.clone());
         ImageUI::build_center(&mut builder, application_vertical_widget.clone());
+        ImageUI::build_filmstrip(&mut builder, application_vertical_widget.clone());
         ImageUI::build_bottom_toolbar(&mut builder, application_vertical_widget.clone());
 
+        ImageUI::load_css();
+
         builder.build().unwrap()
     }
 
+    fn load_css() {
+        let provider = CssProvider::new();
+        provider.load_from_data(APPLICATION_CSS.as_bytes());
+        StyleContext::add_provider_for_display(
+            &Display::default().expect("Could not connect to a display."),
+            &provider,
+            STYLE_PROVIDER_PRIORITY_APPLICATION,
+        );
+    }
+
     fn build_header(builder: &mut ImageUIBuilder, application_vertical_widget: Arc<Box>) {
+        let window_title = Arc::new(WindowTitle::new(APPLICATION_TITLE, ""));
         let header_bar = HeaderBar::builder()
-            .title_widget(&adw::WindowTitle::new("Focus Annotator", ""))
+            .title_widget(window_title.as_ref())
             .build();
 
-        // TODO: add button functionality
-        let open_button = Arc::new(SplitButton::builder().label("Open").build());
+        let recent_files = Config::load().recent_files;
+        let recent_menu = Menu::new();
+        ImageUI::fill_recent_menu(&recent_menu, &recent_files);
+
+        let open_menu = Menu::new();
+        open_menu.append_section(Some("Recent"), &recent_menu);
+        open_menu.append(Some("Open in New Tab…"), Some("win.open_in_tab"));
+        open_menu.append(Some("Import Folder…"), Some("win.import_folder"));
+        open_menu.append(Some("Tile Z-Stack Images…"), Some("win.tile_folder"));
+        open_menu.append(Some("Export Focus Maps…"), Some("win.export_focus_maps"));
+        open_menu.append(
+            Some("Export All in Focus Image…"),
+            Some("win.export_all_in_focus"),
+        );
+
+        let open_button = Arc::new(
+            SplitButton::builder()
+                .label("Open")
+                .menu_model(&open_menu)
+                .build(),
+        );
         header_bar.pack_start(open_button.as_ref());
+
+        let main_menu = Menu::new();
+        main_menu.append(Some("Save"), Some("win.save"));
+        main_menu.append(Some("Save As…"), Some("win.save_as"));
+        main_menu.append(Some("Preferences"), Some("win.preferences"));
+        main_menu.append(Some("Keyboard Shortcuts…"), Some("win.edit_shortcuts"));
+        let main_menu_button = MenuButton::builder()
+            .icon_name("open-menu-symbolic")
+            .menu_model(&main_menu)
+            .build();
+        header_bar.pack_end(&main_menu_button);
+
         application_vertical_widget.append(&header_bar);
 
-        builder.open_button(open_button);
+        builder
+            .open_button(open_button)
+            .recent_menu(recent_menu)
+            .recent_files(Rc::new(RefCell::new(recent_files)))
+            .window_title(window_title);
+
+        ImageUI::build_display_controls(builder, &header_bar);
+    }
+
+    fn build_display_controls(builder: &mut ImageUIBuilder, header_bar: &HeaderBar) {
+        let defaults = DisplaySettings::default();
+        let adjustment_scale = |min: f64, max: f64, value: f32| {
+            let scale = Scale::with_range(Orientation::Horizontal, min, max, 0.01);
+            scale.set_value(value as f64);
+            scale.set_hexpand(true);
+            scale.set_width_request(200);
+            scale
+        };
+        let brightness_scale = adjustment_scale(-1.0, 1.0, defaults.brightness);
+        let contrast_scale = adjustment_scale(0.0, 4.0, defaults.contrast);
+        let gamma_scale = adjustment_scale(0.1, 5.0, defaults.gamma);
+
+        let auto_levels_checkbox = CheckButton::builder()
+            .label("auto levels")
+            .active(defaults.auto_levels)
+            .build();
+        let channel_labels: Vec<&str> = Channel::ALL.iter().map(|x| x.label()).collect();
+        let channel_dropdown = DropDown::from_strings(&channel_labels);
+        let overlay_labels: Vec<&str> = Overlay::ALL.iter().map(|x| x.label()).collect();
+        let overlay_dropdown = DropDown::from_strings(&overlay_labels);
+        let align_planes_checkbox = CheckButton::builder()
+            .label("align planes")
+            .tooltip_text("Correct the sideways drift between the focus levels of a stack")
+            .build();
+        let reset_display_button = Button::builder().label("Reset").build();
+
+        let controls = Grid::builder()
+            .column_spacing(12)
+            .row_spacing(6)
+            .margin_top(6)
+            .margin_bottom(6)
+            .margin_start(6)
+            .margin_end(6)
+            .build();
+        controls.attach(&Label::new(Some("Brightness")), 0, 0, 1, 1);
+        controls.attach(&brightness_scale, 1, 0, 1, 1);
+        controls.attach(&Label::new(Some("Contrast")), 0, 1, 1, 1);
+        controls.attach(&contrast_scale, 1, 1, 1, 1);
+        controls.attach(&Label::new(Some("Gamma")), 0, 2, 1, 1);
+        controls.attach(&gamma_scale, 1, 2, 1, 1);
+        controls.attach(&Label::new(Some("Channel")), 0, 3, 1, 1);
+        controls.attach(&channel_dropdown, 1, 3, 1, 1);
+        controls.attach(&Label::new(Some("Overlay")), 0, 4, 1, 1);
+        controls.attach(&overlay_dropdown, 1, 4, 1, 1);
+        controls.attach(&auto_levels_checkbox, 0, 5, 1, 1);
+        controls.attach(&align_planes_checkbox, 1, 5, 1, 1);
+        controls.attach(&reset_display_button, 1, 6, 1, 1);
+
+        let display_button = MenuButton::builder()
+            .icon_name("display-brightness-symbolic")
+            .tooltip_text("Display")
+            .popover(&Popover::builder().child(&controls).build())
+            .build();
+        header_bar.pack_end(&display_button);
+
+        builder
+            .brightness_scale(brightness_scale)
+            .contrast_scale(contrast_scale)
+            .gamma_scale(gamma_scale)
+            .auto_levels_checkbox(auto_levels_checkbox)
+            .channel_dropdown(channel_dropdown)
+            .overlay_dropdown(overlay_dropdown)
+            .align_planes_checkbox(align_planes_checkbox)
+            .reset_display_button(reset_display_button);
     }
 
     fn build_center(builder: &mut ImageUIBuilder, application_vertical_widget: Arc<Box>) {
         let individual = Arc::new(Image::builder().vexpand(true).hexpand(true).build());
         let center = Arc::new(Image::builder().vexpand(true).hexpand(true).build());
-        let neighbours = [
-            Arc::new(Image::builder().vexpand(true).hexpand(true).build()),
-            Arc::new(Image::builder().vexpand(true).hexpand(true).build()),
-            Arc::new(Image::builder().vexpand(true).hexpand(true).build()),
-            Arc::new(Image::builder().vexpand(true).hexpand(true).build()),
-            Arc::new(Image::builder().vexpand(true).hexpand(true).build()),
-            Arc::new(Image::builder().vexpand(true).hexpand(true).build()),
-            Arc::new(Image::builder().vexpand(true).hexpand(true).build()),
-            Arc::new(Image::builder().vexpand(true).hexpand(true).build()),
-        ];
 
         let focus_scale = Arc::new(
             Scale::builder()
@@ -118,18 +350,14 @@ impl ImageUI {
                 .build(),
         );
 
-        focus_neighbours_grid.attach(center.as_ref(), 1, 1, 1, 1);
-
-        for index in 0..neighbours.len() {
-            // offset index for later images to leave out middle of the grid
-            let grid_index: i32 = if index > 3 { index + 1 } else { index }
-                .try_into()
-                .unwrap();
-            let column = grid_index % 3;
-            let row = grid_index / 3;
-            focus_neighbours_grid.attach(neighbours[index].as_ref(), column, row, 1, 1);
-            eprintln!("{column} {row}");
-        }
+        let neighbours = Rc::new(RefCell::new(Vec::new()));
+        let grid_radius = NeighbourSettings::default().radius;
+        ImageUI::build_neighbour_grid(
+            &focus_neighbours_grid,
+            &center,
+            &mut neighbours.borrow_mut(),
+            grid_radius,
+        );
 
         let center_content_seperator = Separator::new(Orientation::Vertical);
         let center_content = Box::builder()
@@ -144,44 +372,248 @@ impl ImageUI {
                 .yalign(0.5)
                 .build(),
         );
-        focus_neighbours_aspect_frame.set_child(Some(individual.as_ref()));
+        let region_drawing = Rc::new(RefCell::new(RegionDrawing::default()));
+        let region_area = Arc::new(DrawingArea::builder().vexpand(true).hexpand(true).build());
+        let _individual = individual.clone();
+        let _region_drawing = region_drawing.clone();
+        region_area.set_draw_func(move |_, context, width, height| {
+            let bounds = ImageUI::fitted_bounds(&_individual, width as f64, height as f64);
+            ImageUI::draw_regions(context, bounds, &_region_drawing.borrow());
+        });
+
+        let individual_view = Arc::new(gtk::Overlay::builder().child(individual.as_ref()).build());
+        individual_view.add_overlay(region_area.as_ref());
+        focus_neighbours_aspect_frame.set_child(Some(individual_view.as_ref()));
+
+        let mosaic_image = Arc::new(Image::builder().vexpand(true).hexpand(true).build());
+
+        let pinned_image = Arc::new(Image::builder().vexpand(true).hexpand(true).build());
+        let comparison_image = Arc::new(Image::builder().vexpand(true).hexpand(true).build());
+        let comparison_box = Arc::new(
+            Box::builder()
+                .orientation(Orientation::Horizontal)
+                .homogeneous(true)
+                .spacing(4)
+                .build(),
+        );
+        comparison_box.append(pinned_image.as_ref());
+        comparison_box.append(comparison_image.as_ref());
+
+        let all_in_focus_current_image =
+            Arc::new(Image::builder().vexpand(true).hexpand(true).build());
+        let all_in_focus_image = Arc::new(
+            Image::builder()
+                .vexpand(true)
+                .hexpand(true)
+                .tooltip_text("All in focus")
+                .build(),
+        );
+        let all_in_focus_box = Arc::new(
+            Box::builder()
+                .orientation(Orientation::Horizontal)
+                .homogeneous(true)
+                .spacing(4)
+                .build(),
+        );
+        all_in_focus_box.append(all_in_focus_current_image.as_ref());
+        all_in_focus_box.append(all_in_focus_image.as_ref());
 
         center_content.append(focus_scale.as_ref());
         center_content.append(&center_content_seperator);
-        center_content.append(focus_neighbours_aspect_frame.as_ref());
+        let image_scrolled_window = Arc::new(
+            ScrolledWindow::builder()
+                .vexpand(true)
+                .hexpand(true)
+                .child(focus_neighbours_aspect_frame.as_ref())
+                .build(),
+        );
+        center_content.append(image_scrolled_window.as_ref());
+
+        // The image view moves into the page of the selected tab, other pages stay empty
+        let center_content = Arc::new(center_content);
+        let tab_view = TabView::new();
+        tab_view.set_vexpand(true);
+        let first_page = Box::new(Orientation::Horizontal, 0);
+        first_page.append(center_content.as_ref());
+        tab_view.append(&first_page).set_title(EMPTY_TAB_TITLE);
+        let tab_bar = TabBar::builder().view(&tab_view).autohide(true).build();
 
-        application_vertical_widget.append(&center_content);
+        application_vertical_widget.append(&tab_bar);
+        application_vertical_widget.append(&tab_view);
 
         builder
             .focus_scale(focus_scale)
             .focus_neighbours_grid(focus_neighbours_grid)
             .focus_neighbours_aspect_frame(focus_neighbours_aspect_frame)
+            .image_scrolled_window(image_scrolled_window)
+            .center_content(center_content)
+            .tab_view(tab_view)
+            .tabs_updating(Rc::new(Cell::new(false)))
+            .zoom(Rc::new(Cell::new(1.0)))
+            .mosaic_image(mosaic_image)
+            .comparison_box(comparison_box)
+            .pinned_image(pinned_image)
+            .comparison_image(comparison_image)
+            .all_in_focus_box(all_in_focus_box)
+            .all_in_focus_current_image(all_in_focus_current_image)
+            .all_in_focus_image(all_in_focus_image)
+            .all_in_focus_cache(Rc::new(RefCell::new(None)))
             .individual(individual)
+            .individual_view(individual_view)
+            .region_area(region_area)
+            .region_drawing(region_drawing)
             .center(center)
-            .neighbours(neighbours);
+            .neighbours(neighbours)
+            .grid_radius(Rc::new(Cell::new(grid_radius)));
+    }
+
+    // Fills the grid with a square of (2 * radius + 1)^2 images, the center image in the middle
+    fn build_neighbour_grid(
+        grid: &Grid,
+        center: &Image,
+        neighbours: &mut Vec<((i32, i32), Image)>,
+        radius: usize,
+    ) {
+        for (_, image) in neighbours.drain(..) {
+            grid.remove(&image);
+        }
+        grid.remove(center);
+
+        let radius = radius as i32;
+        grid.attach(center, radius, radius, 1, 1);
+        for (dx, dy) in Neighbours::square_offsets(radius as usize) {
+            let image = Image::builder().vexpand(true).hexpand(true).build();
+            grid.attach(&image, dx + radius, dy + radius, 1, 1);
+            neighbours.push(((dx, dy), image));
+        }
+    }
+
+    fn build_filmstrip(builder: &mut ImageUIBuilder, application_vertical_widget: Arc<Box>) {
+        let filmstrip = Arc::new(
+            Box::builder()
+                .orientation(Orientation::Horizontal)
+                .spacing(4)
+                .margin_top(4)
+                .margin_bottom(4)
+                .build(),
+        );
+        let filmstrip_scrolled_window = ScrolledWindow::builder()
+            .hscrollbar_policy(PolicyType::Automatic)
+            .vscrollbar_policy(PolicyType::Never)
+            .hexpand(true)
+            .child(filmstrip.as_ref())
+            .build();
+
+        let filmstrip_sharpness_checkbox = CheckButton::builder()
+            .label("sharpness")
+            .margin_start(MARGIN_LEFT)
+            .build();
+
+        let filmstrip_row = Box::builder()
+            .orientation(Orientation::Horizontal)
+            .spacing(8)
+            .build();
+        filmstrip_row.append(&filmstrip_sharpness_checkbox);
+        filmstrip_row.append(&Separator::new(Orientation::Vertical));
+        filmstrip_row.append(&filmstrip_scrolled_window);
+
+        application_vertical_widget.append(&Separator::new(Orientation::Horizontal));
+        application_vertical_widget.append(&filmstrip_row);
+
+        builder
+            .filmstrip(filmstrip)
+            .filmstrip_sharpness_checkbox(filmstrip_sharpness_checkbox);
     }
 
+    // Buttons of the toolbar do not keep the keyboard focus when they are clicked, so the compare
+    // key that is pressed next does not activate them
     fn build_bottom_toolbar(builder: &mut ImageUIBuilder, application_vertical_widget: Arc<Box>) {
         let bottom_toolbar = ActionBar::builder().build();
 
-        let back_button = Arc::new(Button::builder().label("Back").build());
+        let back_button = Arc::new(
+            Button::builder()
+                .label("Back")
+                .focus_on_click(false)
+                .build(),
+        );
 
-        let skip_button = Arc::new(Button::builder().label("Skip").build());
+        let skip_button = Arc::new(
+            Button::builder()
+                .label("Skip")
+                .focus_on_click(false)
+                .build(),
+        );
 
         let focus_button = Arc::new(
             Button::builder()
                 .label("Set Focus")
                 .css_classes(vec!["suggested-action".to_string()])
+                .focus_on_click(false)
                 .build(),
         );
 
         let neighbour_toggle_button = ToggleButton::builder()
             .label(TOGGLE_NEIGHBOURS_TEXT)
             .width_request(158)
+            .focus_on_click(false)
+            .build();
+
+        let pin_button = Arc::new(
+            Button::builder()
+                .label("Pin")
+                .tooltip_text("Pin the current focus level for comparison")
+                .focus_on_click(false)
+                .build(),
+        );
+        let split_view_toggle_button = ToggleButton::builder()
+            .label("Side by Side")
+            .focus_on_click(false)
+            .build();
+        let flicker_toggle_button = ToggleButton::builder()
+            .label("Flicker")
+            .focus_on_click(false)
+            .build();
+        let all_in_focus_toggle_button = ToggleButton::builder()
+            .label("All in Focus")
+            .tooltip_text(
+                "Show the focus level next to a composite of the sharpest parts of all levels",
+            )
+            .focus_on_click(false)
+            .build();
+
+        let compare_link_widget = Box::builder()
+            .css_classes(vec!["linked".to_string()])
+            .build();
+        compare_link_widget.append(pin_button.as_ref());
+        compare_link_widget.append(&split_view_toggle_button);
+        compare_link_widget.append(&flicker_toggle_button);
+        compare_link_widget.append(&all_in_focus_toggle_button);
+
+        let mark_region_button = Arc::new(
+            Button::builder()
+                .label("Mark Region")
+                .tooltip_text("Mark the current focus level for the selected region")
+                .focus_on_click(false)
+                .build(),
+        );
+        let remove_region_button = Arc::new(
+            Button::builder()
+                .icon_name("edit-delete-symbolic")
+                .tooltip_text("Remove the selected region")
+                .focus_on_click(false)
+                .build(),
+        );
+        let region_link_widget = Box::builder()
+            .css_classes(vec!["linked".to_string()])
             .build();
+        region_link_widget.append(mark_region_button.as_ref());
+        region_link_widget.append(remove_region_button.as_ref());
 
-        let skip_marked_checkbox = CheckButton::builder().label("skip marked").build();
-        skip_marked_checkbox.activate();
+        let skip_marked_checkbox = CheckButton::builder()
+            .label("skip marked")
+            .active(Preferences::default().skip_marked)
+            .focus_on_click(false)
+            .build();
 
         let focus_skip_link_widget = Box::builder()
             .css_classes(vec!["linked".to_string()])
@@ -190,7 +622,46 @@ impl ImageUI {
         focus_skip_link_widget.append(skip_button.as_ref());
         focus_skip_link_widget.append(focus_button.as_ref());
 
-        bottom_toolbar.pack_start(&neighbour_toggle_button);
+        let neighbour_defaults = NeighbourSettings::default();
+        let mosaic_checkbox = CheckButton::builder()
+            .label("seamless mosaic")
+            .active(neighbour_defaults.mosaic)
+            .build();
+        let outline_centre_checkbox = CheckButton::builder()
+            .label("outline centre")
+            .active(neighbour_defaults.outline_centre)
+            .build();
+        let own_focus_checkbox = CheckButton::builder()
+            .label("neighbours at their own focus")
+            .tooltip_text("Show annotated neighbours at the focus level marked for them")
+            .active(neighbour_defaults.own_focus)
+            .build();
+        let neighbourhood_dropdown = DropDown::from_strings(&NEIGHBOURHOOD_SIZES);
+        neighbourhood_dropdown.set_selected((neighbour_defaults.radius - 1) as u32);
+
+        let neighbour_options = Box::builder()
+            .orientation(Orientation::Vertical)
+            .spacing(6)
+            .build();
+        neighbour_options.append(&mosaic_checkbox);
+        neighbour_options.append(&outline_centre_checkbox);
+        neighbour_options.append(&own_focus_checkbox);
+        neighbour_options.append(&neighbourhood_dropdown);
+        let neighbour_options_button = MenuButton::builder()
+            .tooltip_text("Neighbour display")
+            .focus_on_click(false)
+            .popover(&Popover::builder().child(&neighbour_options).build())
+            .build();
+
+        let neighbour_link_widget = Box::builder()
+            .css_classes(vec!["linked".to_string()])
+            .build();
+        neighbour_link_widget.append(&neighbour_toggle_button);
+        neighbour_link_widget.append(&neighbour_options_button);
+
+        bottom_toolbar.pack_start(&neighbour_link_widget);
+        bottom_toolbar.pack_start(&compare_link_widget);
+        bottom_toolbar.pack_start(&region_link_widget);
 
         bottom_toolbar.pack_end(&focus_skip_link_widget);
         bottom_toolbar.pack_end(&skip_marked_checkbox);
@@ -199,12 +670,52 @@ impl ImageUI {
 
         builder
             .neighbour_toggle_button(neighbour_toggle_button)
+            .mosaic_checkbox(mosaic_checkbox)
+            .outline_centre_checkbox(outline_centre_checkbox)
+            .own_focus_checkbox(own_focus_checkbox)
+            .neighbourhood_dropdown(neighbourhood_dropdown)
+            .pin_button(pin_button)
+            .mark_region_button(mark_region_button)
+            .remove_region_button(remove_region_button)
+            .split_view_toggle_button(split_view_toggle_button)
+            .flicker_toggle_button(flicker_toggle_button)
+            .all_in_focus_toggle_button(all_in_focus_toggle_button)
             .skip_marked_checkbox(skip_marked_checkbox)
             .back_button(back_button)
             .skip_button(skip_button)
             .focus_button(focus_button);
     }
 
+    pub fn get_display_settings(&self) -> DisplaySettings {
+        DisplaySettings {
+            brightness: self.brightness_scale.value() as f32,
+            contrast: self.contrast_scale.value() as f32,
+            gamma: self.gamma_scale.value() as f32,
+            auto_levels: self.auto_levels_checkbox.is_active(),
+            channel: Channel::ALL
+                .get(self.channel_dropdown.selected() as usize)
+                .cloned()
+                .unwrap_or(Channel::All),
+        }
+    }
+
+    pub fn get_overlay(&self) -> Overlay {
+        Overlay::ALL
+            .get(self.overlay_dropdown.selected() as usize)
+            .cloned()
+            .unwrap_or(Overlay::None)
+    }
+
+    pub fn reset_display_controls(&self) {
+        let defaults = DisplaySettings::default();
+        self.brightness_scale.set_value(defaults.brightness as f64);
+        self.contrast_scale.set_value(defaults.contrast as f64);
+        self.gamma_scale.set_value(defaults.gamma as f64);
+        self.auto_levels_checkbox.set_active(defaults.auto_levels);
+        self.channel_dropdown.set_selected(0);
+        self.overlay_dropdown.set_selected(0);
+    }
+
     pub fn show(&self) {
         self.window.show();
     }
@@ -223,19 +734,94 @@ impl ImageUI {
                 self.focus_scale
                     .set_value(self.focus_scale.value() - SCALE_STEP);
             }
+            Message::UI(UIMessage::JumpFocus(jump)) => {
+                self.jump_focus(*jump, &state);
+            }
+            Message::UI(UIMessage::FocusDigit(digit)) => {
+                self.focus_digit(*digit, &state);
+            }
+            Message::UI(UIMessage::Zoom(factor, x, y)) => {
+                self.set_zoom(self.zoom.get() * factor, *x, *y);
+            }
+            Message::UI(UIMessage::ResetZoom) => {
+                self.set_zoom(1.0, 0.0, 0.0);
+            }
             Message::UI(UIMessage::ShowGrid(true)) => {
-                self.focus_neighbours_aspect_frame
-                    .set_child(Some(self.focus_neighbours_grid.as_ref()));
+                self.split_view_toggle_button.set_active(false);
+                self.all_in_focus_toggle_button.set_active(false);
                 self.neighbour_toggle_button
                     .set_label(TOGGLE_NEIGHBOURS_TEXT_TOGGLED);
+                self.update_view();
+                self.update_neighbour_grid(state);
+                self.update_mosaic(state);
+            }
+            Message::NeighbourSettingsChanged(_) => {
+                self.update_view();
+                self.update_neighbour_grid(state);
+                self.update_mosaic(state);
             }
             Message::UI(UIMessage::ShowGrid(false)) => {
-                self.focus_neighbours_aspect_frame
-                    .set_child(Some(self.individual.as_ref()));
                 self.neighbour_toggle_button
                     .set_label(TOGGLE_NEIGHBOURS_TEXT);
+                self.update_view();
+            }
+            Message::UI(UIMessage::ToggleSplitView) => {
+                let curent_state = self.split_view_toggle_button.is_active();
+                self.split_view_toggle_button.set_active(!curent_state)
+            }
+            Message::UI(UIMessage::ShowSplitView(value)) => {
+                if *value {
+                    self.neighbour_toggle_button.set_active(false);
+                    self.all_in_focus_toggle_button.set_active(false);
+                }
+                self.update_view();
+                self.update_comparison(state);
+            }
+            Message::UI(UIMessage::ToggleAllInFocus) => {
+                let curent_state = self.all_in_focus_toggle_button.is_active();
+                self.all_in_focus_toggle_button.set_active(!curent_state)
+            }
+            Message::UI(UIMessage::ShowAllInFocus(value)) => {
+                if *value {
+                    self.neighbour_toggle_button.set_active(false);
+                    self.split_view_toggle_button.set_active(false);
+                }
+                self.update_view();
+                self.update_all_in_focus(state);
             }
-            Message::UI(UIMessage::OpenFileChooser) => {
+            Message::UI(UIMessage::ToggleFlicker) => {
+                let curent_state = self.flicker_toggle_button.is_active();
+                self.flicker_toggle_button.set_active(!curent_state)
+            }
+            Message::UI(UIMessage::Flicker(value)) => {
+                if let Some(source_id) = self.flicker_source.borrow_mut().take() {
+                    source_id.remove();
+                }
+                if *value {
+                    let _sender = self.sender.clone();
+                    let source_id =
+                        timeout_add_local(Duration::from_millis(FLICKER_INTERVAL_MS), move || {
+                            _sender.send(Message::TogglePinned).unwrap();
+                            Continue(true)
+                        });
+                    self.flicker_source.replace(Some(source_id));
+                } else {
+                    self.sender.send(Message::ShowPinned(false)).unwrap();
+                }
+            }
+            Message::UI(UIMessage::ToggleOverlay(overlay)) => {
+                let overlay = if self.get_overlay() == *overlay {
+                    Overlay::None
+                } else {
+                    *overlay
+                };
+                if let Some(index) = Overlay::ALL.iter().position(|x| *x == overlay) {
+                    self.overlay_dropdown.set_selected(index as u32);
+                }
+            }
+            Message::UI(UIMessage::OpenFileChooser)
+            | Message::UI(UIMessage::OpenTabFileChooser) => {
+                let new_tab = matches!(msg, Message::UI(UIMessage::OpenTabFileChooser));
                 let file_chooser_action = FileChooserAction::Open;
                 let buttons = [("Open", ResponseType::Ok), ("Cancel", ResponseType::Cancel)];
                 let filter = FileFilter::new();
@@ -256,7 +842,11 @@ impl ImageUI {
                         if response == ResponseType::Ok {
                             let file = dialog.file().expect("Couldn't get file");
                             eprintln!("Open");
-                            _sender.send(Message::OpenFile(file)).unwrap();
+                            let msg = match new_tab {
+                                true => Message::OpenFileInTab(file),
+                                false => Message::OpenFile(file),
+                            };
+                            _sender.send(msg).unwrap();
                         }
                         dialog.close();
                     },
@@ -264,73 +854,1940 @@ impl ImageUI {
 
                 file_chooser.show();
             }
+            Message::UI(UIMessage::OpenImportChooser) => {
+                self.show_import_folder_chooser(false);
+            }
+            Message::UI(UIMessage::OpenTileChooser) => {
+                self.show_import_folder_chooser(true);
+            }
+            Message::UI(UIMessage::OpenFocusMapExportChooser) => {
+                self.show_focus_map_export_chooser();
+            }
+            Message::UI(UIMessage::OpenAllInFocusExportChooser) => {
+                self.show_all_in_focus_export_chooser();
+            }
+            Message::ExportFocusMaps(dir, options) => {
+                self.export_focus_maps(dir, options, &state);
+            }
+            Message::UI(UIMessage::OpenSaveAsChooser) => {
+                self.show_save_as_chooser(&state);
+            }
+            Message::UI(UIMessage::OpenShortcutsDialog) => {
+                self.show_shortcuts_dialog();
+            }
+            Message::UI(UIMessage::OpenPreferences) => {
+                self.show_preferences();
+            }
+            Message::PreferencesChanged(preferences) => {
+                self.apply_preferences(preferences);
+            }
             Message::UI(UIMessage::RefreshImages)
             | Message::NextImage
             | Message::PreviousImage
             | Message::MarkFocus
             | Message::FocusLevelChange(_)
-            | Message::OpenFile(_) => {
+            | Message::OpenFile(_)
+            | Message::OpenFileInTab(_)
+            | Message::SelectTab(_)
+            | Message::CloseTab(_)
+            | Message::ImportFolder(_, _)
+            | Message::Reload
+            | Message::Merge
+            | Message::TakeOverLock
+            | Message::DisplaySettingsChanged(_)
+            | Message::OverlayChanged(_)
+            | Message::AlignPlanes(_)
+            | Message::PlaneShifts(_, _)
+            | Message::MarkRegionFocus
+            | Message::PinFocus
+            | Message::ShowPinned(_)
+            | Message::TogglePinned => {
                 match (
-                    state.get_current_annotation_image(),
+                    state.get_displayed_annotation_image(),
                     state.root_path.clone(),
                 ) {
                     (Some(annotation_image), Some(base_path)) => {
-                        self.update_image(&annotation_image, base_path)
+                        if let Message::OpenFile(_)
+                        | Message::ImportFolder(_, _)
+                        | Message::OpenFileInTab(_)
+                        | Message::SelectTab(_)
+                        | Message::CloseTab(_) = msg
+                        {
+                            self.select_neighbourhood(annotation_image.neighbours.radius());
+                        }
+                        self.update_image(&annotation_image, base_path, state)
                     }
                     (_, _) => {
                         // TODO: write error message
+                        if !state.has_file() {
+                            self.clear_images();
+                        }
                     }
                 }
+                // Another dataset, one read again or one in another tab may have the same root
+                // path and stack indices, the display settings change the thumbnails and the
+                // alignment both
+                if let Message::OpenFile(_)
+                | Message::OpenFileInTab(_)
+                | Message::SelectTab(_)
+                | Message::CloseTab(_)
+                | Message::ImportFolder(_, _)
+                | Message::Reload
+                | Message::Merge
+                | Message::TakeOverLock
+                | Message::DisplaySettingsChanged(_)
+                | Message::AlignPlanes(_)
+                | Message::PlaneShifts(_, _) = msg
+                {
+                    self.filmstrip_stack.replace(None);
+                    self.all_in_focus_cache.replace(None);
+                }
+                if let Message::PlaneShifts(_, _) = msg {
+                    self.registering.set(false);
+                }
+                if let Message::OpenFile(_)
+                | Message::ImportFolder(_, _)
+                | Message::OpenFileInTab(_)
+                | Message::Reload
+                | Message::Merge
+                | Message::MarkFocus = msg
+                {
+                    self.update_recent_files(&state);
+                }
+                if let Message::OpenFile(_)
+                | Message::ImportFolder(_, _)
+                | Message::OpenFileInTab(_)
+                | Message::CloseTab(_) = msg
+                {
+                    self.save_recent_files();
+                }
                 self.update_focus_scale(&state);
+                self.update_comparison(&state);
+                self.update_all_in_focus(&state);
+                self.update_neighbour_grid(&state);
+                self.update_mosaic(&state);
+                self.update_filmstrip(&state);
+                self.update_regions(&state);
+                self.register_stack(&state);
+            }
+            Message::AddRegion(_, _, _, _) | Message::SelectRegion(_) | Message::RemoveRegion => {
+                self.update_regions(&state);
+            }
+            Message::UI(UIMessage::ShowSharpness(_)) => {
+                self.filmstrip_stack.replace(None);
+                self.update_filmstrip(&state);
+            }
+            // The progress is written with the dataset, the title shows whether the dataset is
+            // saved, see `update_tabs`
+            Message::Quit | Message::SaveAs(_) | Message::Save | Message::Autosave => {
+                self.update_recent_files(&state);
+                self.save_recent_files();
             }
-            Message::Quit | Message::SkipMarkedToogled(_) => {}
+            Message::SkipMarkedToogled(_) => {}
+            // Written by the state, a failed export is shown as its error
+            Message::ExportAllInFocus(_) => {}
+        }
+
+        self.notify_conflict(msg, state);
+        self.notify_lock(msg, state);
+        if let Some(error) = state.get_error() {
+            self.show_message("Something Went Wrong", error);
         }
     }
-    fn update_image(&self, annotation_image: &AnnotationImage, base_path: String) {
-        self.individual.set_from_file(Some(
-            Path::new(&base_path).join(Path::new(&annotation_image.image_path)),
-        ));
-        self.center.set_from_file(Some(
-            Path::new(&base_path).join(Path::new(&annotation_image.image_path)),
-        ));
 
-        for index in 0..annotation_image.neighbours.len() {
-            self.neighbours[index].set_from_file(
-                annotation_image.neighbours[index]
-                    .clone()
-                    .map(|x| Path::new(&base_path).join(Path::new(&x))),
-            );
+    // Closes a tab, the annotations of a read-only dataset are lost unless they are saved to
+    // another file, so closing its tab has to be confirmed
+    pub fn close_tab(&self, index: usize, state: &State) {
+        if !state.is_read_only() || !state.is_dirty() {
+            self.sender.send(Message::CloseTab(index)).unwrap();
+            return;
         }
+        let _sender = self.sender.clone();
+        self.confirm_discard(index, state, "Close Without Saving", move || {
+            _sender.send(Message::CloseTab(index)).unwrap();
+        });
     }
 
-    fn update_focus_scale(&self, state: &State) {
-        let max = state.get_current_foucs_stack_max().unwrap_or(0) as f64;
-        self.focus_scale.set_range(0.0, max);
+    // Offers to save the annotations of the read-only dataset in tab `index` with Save As before
+    // `discard` drops them
+    pub fn confirm_discard<F: Fn() + 'static>(
+        &self,
+        index: usize,
+        state: &State,
+        discard_label: &str,
+        discard: F,
+    ) {
+        let buttons = [
+            ("Save As…", ResponseType::Accept),
+            (discard_label, ResponseType::Reject),
+            ("Cancel", ResponseType::Cancel),
+        ];
+        let dialog = Dialog::with_buttons(
+            Some("Unsaved Annotations"),
+            Some(self.window.as_ref()),
+            DialogFlags::MODAL | DialogFlags::DESTROY_WITH_PARENT,
+            &buttons,
+        );
+        let content = dialog.content_area();
+        content.set_margin_top(MARGIN_TOP);
+        content.set_margin_bottom(MARGIN_BOTTOM);
+        content.set_margin_start(MARGIN_LEFT);
+        content.set_margin_end(MARGIN_LEFT);
+        content.append(
+            &Label::builder()
+                .label(&format!(
+                    "{} is read-only, the annotations made here are lost unless they are saved \
+                     to another file with Save As.",
+                    state.get_file_name().unwrap_or_default()
+                ))
+                .wrap(true)
+                .max_width_chars(60)
+                .xalign(0.0)
+                .build(),
+        );
 
-        if let Some(best_index) = state.get_current_foucs_stack_best_index() {
-            self.focus_scale.clear_marks();
-            self.focus_scale
-                .add_mark(best_index as f64, PositionType::Right, Some("focus"));
-            self.focus_scale.set_margin_end(0);
-        } else {
-            self.focus_scale.clear_marks();
-            self.focus_scale
-                .set_margin_end(MARGIN_RIGHT_SCALE_ADDITIONAL);
+        let _sender = self.sender.clone();
+        dialog.connect_response(move |dialog: &Dialog, response: ResponseType| {
+            match response {
+                ResponseType::Accept => {
+                    _sender.send(Message::SelectTab(index)).unwrap();
+                    _sender
+                        .send(Message::UI(UIMessage::OpenSaveAsChooser))
+                        .unwrap();
+                }
+                ResponseType::Reject => discard(),
+                _ => {}
+            }
+            dialog.close();
+        });
+        dialog.show();
+    }
+
+    fn show_message(&self, title: &str, text: &str) {
+        let dialog = Dialog::with_buttons(
+            Some(title),
+            Some(self.window.as_ref()),
+            DialogFlags::MODAL | DialogFlags::DESTROY_WITH_PARENT,
+            &[("Close", ResponseType::Close)],
+        );
+        let content = dialog.content_area();
+        content.set_margin_top(MARGIN_TOP);
+        content.set_margin_bottom(MARGIN_BOTTOM);
+        content.set_margin_start(MARGIN_LEFT);
+        content.set_margin_end(MARGIN_LEFT);
+        content.append(
+            &Label::builder()
+                .label(text)
+                .wrap(true)
+                .max_width_chars(60)
+                .xalign(0.0)
+                .build(),
+        );
+        dialog.connect_response(|dialog: &Dialog, _| dialog.close());
+        dialog.show();
+    }
+
+    // Tells who holds the lock of a dataset that was opened read-only
+    fn notify_lock(&self, msg: &Message, state: &State) {
+        let holder = match state.get_lock_holder() {
+            Some(holder) => holder,
+            None => return,
+        };
+        match msg {
+            Message::OpenFile(_)
+            | Message::OpenFileInTab(_)
+            | Message::ImportFolder(_, _)
+            | Message::SaveAs(_)
+            | Message::Save => {}
+            _ => return,
         }
 
-        if let Some(current_value) = state.get_focus_image_index() {
-            self.focus_scale.set_value(current_value as f64);
+        let buttons = [
+            ("Take Over", ResponseType::Accept),
+            ("Open Read-Only", ResponseType::Close),
+        ];
+        let dialog = Dialog::with_buttons(
+            Some("Dataset in Use"),
+            Some(self.window.as_ref()),
+            DialogFlags::MODAL | DialogFlags::DESTROY_WITH_PARENT,
+            &buttons,
+        );
+        let content = dialog.content_area();
+        content.set_spacing(12);
+        content.set_margin_top(MARGIN_TOP);
+        content.set_margin_bottom(MARGIN_BOTTOM);
+        content.set_margin_start(MARGIN_LEFT);
+        content.set_margin_end(MARGIN_LEFT);
+        content.append(
+            &Label::builder()
+                .label(&format!(
+                    "{} is opened by {}.\n\n\
+                     It is read-only here, annotations made here are not saved unless they are \
+                     saved to another file with Save As. Only take over the dataset when the \
+                     other session is no longer used, its unsaved annotations are kept.",
+                    state.get_file_name().unwrap_or_default(),
+                    holder.describe()
+                ))
+                .wrap(true)
+                .max_width_chars(60)
+                .xalign(0.0)
+                .build(),
+        );
+
+        let _sender = self.sender.clone();
+        dialog.connect_response(move |dialog: &Dialog, response: ResponseType| {
+            if response == ResponseType::Accept {
+                _sender.send(Message::TakeOverLock).unwrap();
+            }
+            dialog.close();
+        });
+        dialog.show();
+    }
+
+    // Asks what to do when the save file was changed by another program, once per conflict unless
+    // the user saves again
+    fn notify_conflict(&self, msg: &Message, state: &State) {
+        let path = match state.get_save_path() {
+            Some(path) => path,
+            None => return,
+        };
+        if !state.has_conflict() {
+            self.notified_conflicts.borrow_mut().remove(&path);
+            return;
+        }
+        let notified = self.notified_conflicts.borrow().contains(&path);
+        match msg {
+            Message::Save => {}
+            Message::Autosave | Message::SelectTab(_) | Message::CloseTab(_) if !notified => {}
+            _ => return,
+        }
+        self.notified_conflicts.borrow_mut().insert(path.clone());
+
+        let buttons = [
+            ("Reload", ResponseType::Reject),
+            ("Merge", ResponseType::Apply),
+            ("Save Elsewhere…", ResponseType::Accept),
+            ("Cancel", ResponseType::Cancel),
+        ];
+        let dialog = Dialog::with_buttons(
+            Some("Dataset Changed on Disk"),
+            Some(self.window.as_ref()),
+            DialogFlags::MODAL | DialogFlags::DESTROY_WITH_PARENT,
+            &buttons,
+        );
+        let content = dialog.content_area();
+        content.set_spacing(12);
+        content.set_margin_top(MARGIN_TOP);
+        content.set_margin_bottom(MARGIN_BOTTOM);
+        content.set_margin_start(MARGIN_LEFT);
+        content.set_margin_end(MARGIN_LEFT);
+        content.append(
+            &Label::builder()
+                .label(&format!(
+                    "{} was changed by another program and was not saved over.\n\n\
+                     Reload it and discard the unsaved annotations made here, merge them into it \
+                     or save the dataset to another file. Until then they are kept in the journal \
+                     and autosave is paused.",
+                    path.display()
+                ))
+                .wrap(true)
+                .max_width_chars(60)
+                .xalign(0.0)
+                .build(),
+        );
+
+        let _sender = self.sender.clone();
+        dialog.connect_response(move |dialog: &Dialog, response: ResponseType| {
+            let msg = match response {
+                ResponseType::Reject => Some(Message::Reload),
+                ResponseType::Apply => Some(Message::Merge),
+                ResponseType::Accept => Some(Message::UI(UIMessage::OpenSaveAsChooser)),
+                _ => None,
+            };
+            if let Some(msg) = msg {
+                _sender.send(msg).unwrap();
+            }
+            dialog.close();
+        });
+        dialog.show();
+    }
+    fn show_focus_map_export_chooser(&self) {
+        let buttons = [
+            ("Export", ResponseType::Ok),
+            ("Cancel", ResponseType::Cancel),
+        ];
+        let folder_chooser = FileChooserDialog::new(
+            Some("Chose a folder for the focus maps!"),
+            Some(self.window.as_ref()),
+            FileChooserAction::SelectFolder,
+            &buttons,
+        );
+        folder_chooser.add_choice(
+            "format",
+            "Format",
+            &[
+                (ExportFormat::Png.extension(), "PNG"),
+                (ExportFormat::Tiff.extension(), "TIFF"),
+            ],
+        );
+        folder_chooser.set_choice("format", ExportFormat::Png.extension());
+        folder_chooser.add_choice("guided", "Guide by the marked focus level", &[]);
+
+        let _sender = self.sender.clone();
+        folder_chooser.connect_response(
+            move |dialog: &FileChooserDialog, response: ResponseType| {
+                if response == ResponseType::Ok {
+                    let folder = dialog.file().expect("Couldn't get folder");
+                    let format = dialog
+                        .choice("format")
+                        .and_then(|x| ExportFormat::from_name(&x))
+                        .unwrap_or(ExportFormat::Png);
+                    let mut options = FocusMapOptions::new(format);
+                    options.guided = dialog.choice("guided").map_or(false, |x| x == "true");
+                    if let Some(path) = folder.path() {
+                        _sender
+                            .send(Message::ExportFocusMaps(path, options))
+                            .unwrap();
+                    }
+                }
+                dialog.close();
+            },
+        );
+
+        folder_chooser.show();
+    }
+
+    // Exports the focus maps on a worker thread, so the images can be annotated meanwhile. A
+    // dialog shows the progress and lists the skipped stacks when the export is done.
+    fn export_focus_maps(&self, dir: &Path, options: &FocusMapOptions, state: &State) {
+        let root_path = match &state.root_path {
+            Some(root_path) => PathBuf::from(root_path),
+            None => {
+                self.show_message("Focus Maps Not Exported", "No dataset is opened.");
+                return;
+            }
+        };
+        if self.exporting_focus_maps.get() {
+            self.show_message(
+                "Focus Maps Not Exported",
+                "Focus maps are already being exported, wait until the export is done.",
+            );
+            return;
+        }
+        self.exporting_focus_maps.set(true);
+
+        let dialog = Dialog::with_buttons(
+            Some("Exporting Focus Maps"),
+            Some(self.window.as_ref()),
+            DialogFlags::DESTROY_WITH_PARENT,
+            &[("Close", ResponseType::Close)],
+        );
+        let content = dialog.content_area();
+        content.set_spacing(12);
+        content.set_margin_top(MARGIN_TOP);
+        content.set_margin_bottom(MARGIN_BOTTOM);
+        content.set_margin_start(MARGIN_LEFT);
+        content.set_margin_end(MARGIN_LEFT);
+        let progress_bar = ProgressBar::builder().show_text(true).build();
+        let label = Label::builder()
+            .label(&format!("Writing focus maps to {}", dir.display()))
+            .wrap(true)
+            .max_width_chars(60)
+            .xalign(0.0)
+            .build();
+        content.append(&progress_bar);
+        content.append(
+            &ScrolledWindow::builder()
+                .child(&label)
+                .hscrollbar_policy(PolicyType::Never)
+                .propagate_natural_height(true)
+                .max_content_height(300)
+                .build(),
+        );
+        // Closing the dialog does not stop the export, it shows up again when the export is done
+        dialog.set_hide_on_close(true);
+        dialog.connect_response(|dialog: &Dialog, _| dialog.close());
+        dialog.show();
+
+        let (sender, receiver) = MainContext::channel(PRIORITY_DEFAULT);
+        let stacks = state.get_stacks().to_vec();
+        let (output_dir, options) = (dir.to_path_buf(), options.clone());
+        std::thread::spawn(move || {
+            let result = export::export_focus_maps(
+                &stacks,
+                &root_path,
+                &output_dir,
+                &options,
+                |done, total| {
+                    sender.send(FocusMapExport::Progress(done, total)).unwrap();
+                },
+            );
+            sender.send(FocusMapExport::Done(result)).unwrap();
+        });
+
+        let dir = dir.to_path_buf();
+        let exporting_focus_maps = self.exporting_focus_maps.clone();
+        receiver.attach(None, move |event| match event {
+            FocusMapExport::Progress(done, total) => {
+                if total > 0 {
+                    progress_bar.set_fraction(done as f64 / total as f64);
+                }
+                progress_bar.set_text(Some(&format!("{} of {} focus stacks", done, total)));
+                Continue(true)
+            }
+            FocusMapExport::Done(result) => {
+                let text = match result {
+                    Ok(report) => {
+                        let mut text =
+                            format!("Wrote {} focus maps to {}.", report.written, dir.display());
+                        if !report.skipped.is_empty() {
+                            text.push_str(&format!(
+                                "\n\n{} focus stacks were skipped:",
+                                report.skipped.len()
+                            ));
+                            for (stack_index, reason) in &report.skipped {
+                                text.push_str(&format!("\nstack {}: {}", stack_index, reason));
+                            }
+                        }
+                        text
+                    }
+                    Err(e) => format!("Export of focus maps failed: {}", e),
+                };
+                eprintln!("{}", text);
+                dialog.set_title(Some("Focus Maps Exported"));
+                label.set_label(&text);
+                dialog.set_hide_on_close(false);
+                dialog.present();
+                exporting_focus_maps.set(false);
+                Continue(false)
+            }
+        });
+    }
+
+    fn show_import_folder_chooser(&self, tiling: bool) {
+        let buttons = [
+            ("Select", ResponseType::Ok),
+            ("Cancel", ResponseType::Cancel),
+        ];
+        let title = if tiling {
+            "Chose a folder of full-field z-stack images!"
         } else {
-            self.focus_scale.set_value(f64::floor(max / 2.0));
+            "Chose a folder of image tiles!"
+        };
+        let folder_chooser = FileChooserDialog::new(
+            Some(title),
+            Some(self.window.as_ref()),
+            FileChooserAction::SelectFolder,
+            &buttons,
+        );
+
+        let _self = self.clone();
+        folder_chooser.connect_response(
+            move |dialog: &FileChooserDialog, response: ResponseType| {
+                if response == ResponseType::Ok {
+                    let folder = dialog.file().expect("Couldn't get folder");
+                    if let Some(path) = folder.path() {
+                        _self.show_import_options_dialog(path, tiling);
+                    }
+                }
+                dialog.close();
+            },
+        );
+
+        folder_chooser.show();
+    }
+
+    fn show_import_options_dialog(&self, folder: PathBuf, tiling: bool) {
+        let buttons = [
+            ("Import", ResponseType::Ok),
+            ("Cancel", ResponseType::Cancel),
+        ];
+        let dialog = Dialog::with_buttons(
+            Some("Import options"),
+            Some(self.window.as_ref()),
+            DialogFlags::MODAL | DialogFlags::DESTROY_WITH_PARENT,
+            &buttons,
+        );
+
+        let default_pattern = if tiling {
+            DEFAULT_TILE_PATTERN
+        } else {
+            DEFAULT_IMPORT_PATTERN
+        };
+        let pattern_entry = Entry::builder()
+            .text(default_pattern)
+            .width_chars(60)
+            .build();
+        let tile_size_button = SpinButton::with_range(1.0, 100000.0, 1.0);
+        tile_size_button.set_value(DEFAULT_TILE_SIZE as f64);
+        let tile_overlap_button = SpinButton::with_range(0.0, 100000.0, 1.0);
+        let neighbourhood_dropdown = DropDown::from_strings(&NEIGHBOURHOOD_SIZES);
+
+        let content = dialog.content_area();
+        content.set_spacing(12);
+        content.set_margin_top(MARGIN_TOP);
+        content.set_margin_bottom(MARGIN_BOTTOM);
+        content.set_margin_start(MARGIN_LEFT);
+        content.set_margin_end(MARGIN_LEFT);
+        content.append(&Label::new(Some(
+            "Named groups: z (required), img (ignored), px/py (tile position), any other groups identify the field of view",
+        )));
+        content.append(&pattern_entry);
+        let options = Grid::builder().column_spacing(12).row_spacing(6).build();
+        options.attach(&Label::new(Some("Neighbourhood")), 0, 0, 1, 1);
+        options.attach(&neighbourhood_dropdown, 1, 0, 1, 1);
+        if tiling {
+            options.attach(&Label::new(Some("Tile size")), 0, 1, 1, 1);
+            options.attach(&tile_size_button, 1, 1, 1, 1);
+            options.attach(&Label::new(Some("Overlap")), 0, 2, 1, 1);
+            options.attach(&tile_overlap_button, 1, 2, 1, 1);
         }
+        content.append(&options);
+
+        let _sender = self.sender.clone();
+        dialog.connect_response(move |dialog: &Dialog, response: ResponseType| {
+            if response == ResponseType::Ok {
+                let mut options = ImportOptions::new(pattern_entry.text().to_string());
+                options.neighbourhood_radius = neighbourhood_dropdown.selected() as usize + 1;
+                if tiling {
+                    options.tile_size = Some(tile_size_button.value_as_int() as u32);
+                    options.tile_overlap = tile_overlap_button.value_as_int() as u32;
+                }
+                _sender
+                    .send(Message::ImportFolder(folder.clone(), options))
+                    .unwrap();
+            }
+            dialog.close();
+        });
+
+        dialog.show();
     }
 
-    pub fn setup_shortcuts(app: &Application) {
-        app.set_accels_for_action("win.toggle_neighbour", &["G"]);
-        app.set_accels_for_action("win.increment_focus_scale", &["W"]);
-        app.set_accels_for_action("win.decrement_focus_scale", &["S"]);
-        app.set_accels_for_action("win.mark_focus", &["M"]);
-        app.set_accels_for_action("win.skip_focus", &["N"]);
-        app.set_accels_for_action("win.back_focus", &["B"]);
+    fn update_image(&self, annotation_image: &AnnotationImage, base_path: String, state: &State) {
+        let load = |image: &AnnotationImage| self.load_stack_plane(image, &base_path, state);
+
+        let overlay = state.get_overlay();
+        let previous = match overlay {
+            Overlay::Difference => state
+                .get_previous_annotation_image()
+                .and_then(|image| load(&image)),
+            _ => None,
+        };
+        let pixbuf = load(annotation_image)
+            .map(|plane| ImageUI::plane_to_pixbuf(&plane.overlaid(overlay, previous.as_ref())));
+        self.individual.set_from_pixbuf(pixbuf.as_ref());
+        self.center.set_from_pixbuf(pixbuf.as_ref());
+    }
+
+    // Shows the neighbours in a grid of the selected size, tiles outside of the neighbours stored
+    // with the image are looked up through the neighbouring focus stacks
+    fn update_neighbour_grid(&self, state: &State) {
+        if !self.neighbour_toggle_button.is_active() {
+            return;
+        }
+
+        let neighbour_settings = state.get_neighbour_settings();
+        let radius = neighbour_settings.radius;
+        if radius != self.grid_radius.get() {
+            ImageUI::build_neighbour_grid(
+                &self.focus_neighbours_grid,
+                &self.center,
+                &mut self.neighbours.borrow_mut(),
+                radius,
+            );
+            self.grid_radius.set(radius);
+        }
+
+        let (annotation_image, base_path) = match (
+            state.get_displayed_annotation_image(),
+            state.root_path.clone(),
+        ) {
+            (Some(annotation_image), Some(base_path)) => (annotation_image, base_path),
+            (_, _) => return,
+        };
+
+        for ((dx, dy), image) in self.neighbours.borrow().iter() {
+            let pixbuf = state
+                .get_displayed_neighbour(&annotation_image, *dx, *dy, neighbour_settings.own_focus)
+                .and_then(|(path, page)| {
+                    let path = Path::new(&base_path).join(Path::new(&path));
+                    self.load_pixbuf(&path, page, state.get_display_settings())
+                });
+            image.set_from_pixbuf(pixbuf.as_ref());
+        }
+    }
+
+    // Selects the neighbourhood size stored in a newly opened dataset
+    fn select_neighbourhood(&self, radius: usize) {
+        if radius > 0 {
+            let index = radius.min(NEIGHBOURHOOD_SIZES.len()) - 1;
+            self.neighbourhood_dropdown.set_selected(index as u32);
+        }
+    }
+
+    fn update_regions(&self, state: &State) {
+        {
+            let mut region_drawing = self.region_drawing.borrow_mut();
+            region_drawing.regions = state
+                .get_current_focus_stack()
+                .map(|stack| stack.regions.clone())
+                .unwrap_or_default();
+            region_drawing.selected = state.get_selected_region();
+            region_drawing.focus_index = state.get_focus_image_index();
+        }
+        self.region_area.queue_draw();
+    }
+
+    // Area (x, y, width, height) the image takes up inside of a widget, the image keeps its aspect ratio
+    fn fitted_bounds(image: &Image, width: f64, height: f64) -> (f64, f64, f64, f64) {
+        let aspect_ratio = image
+            .paintable()
+            .map(|x| x.intrinsic_aspect_ratio())
+            .filter(|x| *x > 0.0)
+            .unwrap_or(1.0);
+        let (fitted_width, fitted_height) = if width / height > aspect_ratio {
+            (height * aspect_ratio, height)
+        } else {
+            (width, width / aspect_ratio)
+        };
+        (
+            (width - fitted_width) / 2.0,
+            (height - fitted_height) / 2.0,
+            fitted_width,
+            fitted_height,
+        )
+    }
+
+    fn draw_regions(
+        context: &gtk::cairo::Context,
+        bounds: (f64, f64, f64, f64),
+        region_drawing: &RegionDrawing,
+    ) {
+        let (x, y, width, height) = bounds;
+        context.set_font_size(12.0);
+        for (index, region) in region_drawing.regions.iter().enumerate() {
+            let colour =
+                if region.best_index.is_some() && region.best_index == region_drawing.focus_index {
+                    REGION_FOCUS_COLOUR
+                } else {
+                    REGION_COLOUR
+                };
+            let line_width = if Some(index) == region_drawing.selected {
+                3.0
+            } else {
+                1.5
+            };
+            let (region_x, region_y) = (x + region.x * width, y + region.y * height);
+            context.set_source_rgb(colour[0], colour[1], colour[2]);
+            context.set_line_width(line_width);
+            context.rectangle(
+                region_x,
+                region_y,
+                region.width * width,
+                region.height * height,
+            );
+            context.stroke().ok();
+
+            if let Some(best_index) = region.best_index {
+                context.move_to(region_x + 4.0, region_y + 14.0);
+                context.show_text(&best_index.to_string()).ok();
+            }
+        }
+
+        if let Some((start_x, start_y, offset_x, offset_y)) = region_drawing.drag {
+            context.set_source_rgb(REGION_COLOUR[0], REGION_COLOUR[1], REGION_COLOUR[2]);
+            context.set_line_width(1.0);
+            context.set_dash(&[4.0, 4.0], 0.0);
+            context.rectangle(start_x, start_y, offset_x, offset_y);
+            context.stroke().ok();
+        }
+    }
+
+    // Position inside the region area relative to the displayed tile, clamped to the tile
+    pub fn to_tile_coordinates(&self, x: f64, y: f64) -> (f64, f64) {
+        let (bounds_x, bounds_y, width, height) = ImageUI::fitted_bounds(
+            &self.individual,
+            self.region_area.width() as f64,
+            self.region_area.height() as f64,
+        );
+        (
+            ((x - bounds_x) / width).clamp(0.0, 1.0),
+            ((y - bounds_y) / height).clamp(0.0, 1.0),
+        )
+    }
+
+    // Rectangle in tile coordinates that a drag in the region area draws, limited to the image.
+    // Drags that cover less than `REGION_MIN_SIZE` pixels of the image either way draw nothing.
+    pub fn drag_to_region(
+        &self,
+        x: f64,
+        y: f64,
+        offset_x: f64,
+        offset_y: f64,
+    ) -> Option<(f64, f64, f64, f64)> {
+        let (_, _, width, height) = ImageUI::fitted_bounds(
+            &self.individual,
+            self.region_area.width() as f64,
+            self.region_area.height() as f64,
+        );
+        let (x0, y0) = self.to_tile_coordinates(x.min(x + offset_x), y.min(y + offset_y));
+        let (x1, y1) = self.to_tile_coordinates(x.max(x + offset_x), y.max(y + offset_y));
+        match (x1 - x0) * width >= REGION_MIN_SIZE && (y1 - y0) * height >= REGION_MIN_SIZE {
+            true => Some((x0, y0, x1 - x0, y1 - y0)),
+            false => None,
+        }
+    }
+
+    // Topmost region at a position inside the region area
+    pub fn region_at(&self, x: f64, y: f64) -> Option<usize> {
+        let (x, y) = self.to_tile_coordinates(x, y);
+        self.region_drawing
+            .borrow()
+            .regions
+            .iter()
+            .rposition(|region| region.contains(x, y))
+    }
+
+    // Enlarges the image view inside the scrolled window, the content at (x, y) of the visible
+    // area stays under the pointer
+    fn set_zoom(&self, zoom: f64, x: f64, y: f64) {
+        let zoom = zoom.clamp(1.0, MAX_ZOOM);
+        let factor = zoom / self.zoom.get();
+        self.zoom.set(zoom);
+
+        let width = self.image_scrolled_window.width() as f64;
+        let height = self.image_scrolled_window.height() as f64;
+        if zoom == 1.0 {
+            self.focus_neighbours_aspect_frame.set_size_request(-1, -1);
+        } else {
+            self.focus_neighbours_aspect_frame
+                .set_size_request((width * zoom) as i32, (height * zoom) as i32);
+        }
+
+        for (adjustment, position, size) in [
+            (self.image_scrolled_window.hadjustment(), x, width),
+            (self.image_scrolled_window.vadjustment(), y, height),
+        ] {
+            // The new size is only allocated later, the range is widened now so the value is kept
+            adjustment.set_upper(size * zoom);
+            adjustment.set_page_size(size);
+            adjustment.set_value((adjustment.value() + position) * factor - position);
+        }
+    }
+
+    fn update_view(&self) {
+        if self.split_view_toggle_button.is_active() {
+            self.focus_neighbours_aspect_frame.set_ratio(2.0);
+            self.focus_neighbours_aspect_frame
+                .set_child(Some(self.comparison_box.as_ref()));
+        } else if self.all_in_focus_toggle_button.is_active() {
+            self.focus_neighbours_aspect_frame.set_ratio(2.0);
+            self.focus_neighbours_aspect_frame
+                .set_child(Some(self.all_in_focus_box.as_ref()));
+        } else if self.neighbour_toggle_button.is_active() && self.mosaic_checkbox.is_active() {
+            self.focus_neighbours_aspect_frame.set_ratio(1.0);
+            self.focus_neighbours_aspect_frame
+                .set_child(Some(self.mosaic_image.as_ref()));
+        } else if self.neighbour_toggle_button.is_active() {
+            self.focus_neighbours_aspect_frame.set_ratio(1.0);
+            self.focus_neighbours_aspect_frame
+                .set_child(Some(self.focus_neighbours_grid.as_ref()));
+        } else {
+            self.focus_neighbours_aspect_frame.set_ratio(1.0);
+            self.focus_neighbours_aspect_frame
+                .set_child(Some(self.individual_view.as_ref()));
+        }
+    }
+
+    pub fn get_neighbour_settings(&self) -> NeighbourSettings {
+        NeighbourSettings {
+            mosaic: self.mosaic_checkbox.is_active(),
+            outline_centre: self.outline_centre_checkbox.is_active(),
+            radius: self.neighbourhood_dropdown.selected() as usize + 1,
+            own_focus: self.own_focus_checkbox.is_active(),
+        }
+    }
+
+    fn update_mosaic(&self, state: &State) {
+        let neighbour_settings = state.get_neighbour_settings();
+        if !(neighbour_settings.mosaic && self.neighbour_toggle_button.is_active()) {
+            return;
+        }
+
+        let (annotation_image, base_path) = match (
+            state.get_displayed_annotation_image(),
+            state.root_path.clone(),
+        ) {
+            (Some(annotation_image), Some(base_path)) => (annotation_image, base_path),
+            (_, _) => return,
+        };
+
+        let radius = neighbour_settings.radius as i32;
+        let tiles: Vec<Option<Rc<Plane>>> = (-radius..=radius)
+            .flat_map(|dy| (-radius..=radius).map(move |dx| (dx, dy)))
+            .map(|(dx, dy)| {
+                let (path, page) = state.get_displayed_neighbour(
+                    &annotation_image,
+                    dx,
+                    dy,
+                    neighbour_settings.own_focus,
+                )?;
+                let path = Path::new(&base_path).join(Path::new(&path));
+                match self.plane_cache.borrow_mut().get(&path, page) {
+                    Ok(plane) => Some(Rc::new(plane.adjusted(state.get_display_settings()))),
+                    Err(e) => {
+                        eprintln!("Could not load image {}", e);
+                        None
+                    }
+                }
+            })
+            .collect();
+
+        let overlap = state
+            .get_current_focus_stack()
+            .and_then(|stack| stack.tile_overlap)
+            .unwrap_or(0);
+        let pixbuf = mosaic(&tiles, overlap, neighbour_settings.outline_centre)
+            .map(|plane| ImageUI::plane_to_pixbuf(&plane));
+        self.mosaic_image.set_from_pixbuf(pixbuf.as_ref());
+    }
+
+    // Shows the pinned focus level next to the current one in the side by side view
+    fn update_comparison(&self, state: &State) {
+        if !self.split_view_toggle_button.is_active() {
+            return;
+        }
+
+        let base_path = match state.root_path.clone() {
+            Some(base_path) => base_path,
+            None => return,
+        };
+        let load = |annotation_image: Option<AnnotationImage>| {
+            annotation_image
+                .and_then(|annotation_image| {
+                    self.load_stack_plane(&annotation_image, &base_path, state)
+                })
+                .map(|plane| ImageUI::plane_to_pixbuf(&plane))
+        };
+
+        self.pinned_image
+            .set_from_pixbuf(load(state.get_pinned_annotation_image()).as_ref());
+        self.comparison_image
+            .set_from_pixbuf(load(state.get_current_annotation_image()).as_ref());
+    }
+
+    // Shows the displayed focus level next to the all in focus composite of the stack
+    fn update_all_in_focus(&self, state: &State) {
+        if !self.all_in_focus_toggle_button.is_active() {
+            return;
+        }
+
+        let (root_path, stack_index, stack) = match (
+            state.root_path.clone(),
+            state.get_stack_index(),
+            state.get_current_focus_stack(),
+        ) {
+            (Some(root_path), Some(stack_index), Some(stack)) => (root_path, stack_index, stack),
+            (_, _, _) => return,
+        };
+
+        let displayed = state
+            .get_displayed_annotation_image()
+            .and_then(|image| self.load_stack_plane(&image, &root_path, state))
+            .map(|plane| ImageUI::plane_to_pixbuf(&plane));
+        self.all_in_focus_current_image
+            .set_from_pixbuf(displayed.as_ref());
+
+        let key = (root_path.clone(), stack_index, state.is_aligning_planes());
+        let cached = match &*self.all_in_focus_cache.borrow() {
+            Some((cached_key, composite)) if *cached_key == key => Some(composite.clone()),
+            _ => None,
+        };
+        let composite = cached.or_else(|| {
+            let planes: Option<Vec<Plane>> = stack
+                .images
+                .iter()
+                .map(|image| {
+                    let path = Path::new(&root_path).join(Path::new(&image.image_path));
+                    match self.plane_cache.borrow_mut().get(&path, image.page) {
+                        Ok(plane) => Some(ImageUI::aligned(&plane, image, state)),
+                        Err(e) => {
+                            eprintln!("Could not load image {}", e);
+                            None
+                        }
+                    }
+                })
+                .collect();
+            let composite = Rc::new(all_in_focus(&planes?, SHARPNESS_WINDOW)?);
+            self.all_in_focus_cache
+                .replace(Some((key, composite.clone())));
+            Some(composite)
+        });
+
+        let pixbuf = composite.map(|composite| {
+            ImageUI::plane_to_pixbuf(&composite.adjusted(state.get_display_settings()))
+        });
+        self.all_in_focus_image.set_from_pixbuf(pixbuf.as_ref());
+    }
+
+    fn show_all_in_focus_export_chooser(&self) {
+        let buttons = [("Save", ResponseType::Ok), ("Cancel", ResponseType::Cancel)];
+        let filter = FileFilter::new();
+        filter.add_pattern(r"*.png");
+        filter.add_pattern(r"*.tif");
+        filter.add_pattern(r"*.tiff");
+
+        let file_chooser = FileChooserDialog::new(
+            Some("Save the all in focus image"),
+            Some(self.window.as_ref()),
+            FileChooserAction::Save,
+            &buttons,
+        );
+        file_chooser.set_filter(&filter);
+        file_chooser.set_current_name("all_in_focus.png");
+
+        let _sender = self.sender.clone();
+        file_chooser.connect_response(move |dialog: &FileChooserDialog, response: ResponseType| {
+            if response == ResponseType::Ok {
+                if let Some(path) = dialog.file().and_then(|x| x.path()) {
+                    _sender.send(Message::ExportAllInFocus(path)).unwrap();
+                }
+            }
+            dialog.close();
+        });
+
+        file_chooser.show();
+    }
+
+    fn show_save_as_chooser(&self, state: &State) {
+        let buttons = [("Save", ResponseType::Ok), ("Cancel", ResponseType::Cancel)];
+        let filter = FileFilter::new();
+        filter.add_pattern(r"*.json");
+
+        let file_chooser = FileChooserDialog::new(
+            Some("Save the dataset as"),
+            Some(self.window.as_ref()),
+            FileChooserAction::Save,
+            &buttons,
+        );
+        file_chooser.set_filter(&filter);
+        if let Some(path) = state.get_save_path() {
+            if let Some(dir) = path.parent() {
+                if let Err(e) = file_chooser.set_current_folder(Some(&File::for_path(dir))) {
+                    eprintln!("Could not open {:?}: {}", dir, e);
+                }
+            }
+            if let Some(name) = path.file_name() {
+                file_chooser.set_current_name(&name.to_string_lossy());
+            }
+        }
+
+        let _sender = self.sender.clone();
+        file_chooser.connect_response(move |dialog: &FileChooserDialog, response: ResponseType| {
+            if response == ResponseType::Ok {
+                if let Some(path) = dialog.file().and_then(|x| x.path()) {
+                    _sender.send(Message::SaveAs(path)).unwrap();
+                }
+            }
+            dialog.close();
+        });
+
+        file_chooser.show();
+    }
+
+    fn update_filmstrip(&self, state: &State) {
+        let stack_key = match (state.root_path.clone(), state.get_stack_index()) {
+            (Some(root_path), Some(stack_index)) => Some((root_path, stack_index)),
+            (_, _) => None,
+        };
+        if *self.filmstrip_stack.borrow() != stack_key {
+            self.rebuild_filmstrip(state);
+            self.filmstrip_stack.replace(stack_key);
+        }
+
+        let best_index = state.get_current_foucs_stack_best_index();
+        let focus_index = state.get_focus_image_index();
+        for (index, button) in self.filmstrip_buttons.borrow().iter().enumerate() {
+            for (css_class, active) in [
+                (FILMSTRIP_BEST_CSS_CLASS, best_index == Some(index)),
+                (FILMSTRIP_CURRENT_CSS_CLASS, focus_index == Some(index)),
+            ] {
+                if active {
+                    button.add_css_class(css_class);
+                } else {
+                    button.remove_css_class(css_class);
+                }
+            }
+        }
+    }
+
+    fn rebuild_filmstrip(&self, state: &State) {
+        for button in self.filmstrip_buttons.borrow_mut().drain(..) {
+            self.filmstrip.remove(&button);
+        }
+
+        let (stack, base_path) = match (state.get_current_focus_stack(), state.root_path.clone()) {
+            (Some(stack), Some(base_path)) => (stack, base_path),
+            (_, _) => return,
+        };
+        let paths: Vec<PathBuf> = stack
+            .images
+            .iter()
+            .map(|x| Path::new(&base_path).join(Path::new(&x.image_path)))
+            .collect();
+
+        let sharpness: Vec<Option<f64>> = if self.filmstrip_sharpness_checkbox.is_active() {
+            stack
+                .images
+                .iter()
+                .zip(paths.iter())
+                .map(|(image, path)| self.get_sharpness(path, image.page))
+                .collect()
+        } else {
+            vec![None; paths.len()]
+        };
+        let max_sharpness = sharpness.iter().flatten().cloned().fold(0.0, f64::max);
+
+        for (index, (image, path)) in stack.images.iter().zip(paths.iter()).enumerate() {
+            let thumbnail = Image::builder().pixel_size(THUMBNAIL_SIZE as i32).build();
+            match self.plane_cache.borrow_mut().get(path, image.page) {
+                Ok(plane) => {
+                    let plane = ImageUI::aligned(&plane, image, state)
+                        .thumbnail(THUMBNAIL_SIZE)
+                        .adjusted(state.get_display_settings());
+                    thumbnail.set_from_pixbuf(Some(&ImageUI::plane_to_pixbuf(&plane)));
+                }
+                Err(e) => eprintln!("Could not load image {}", e),
+            }
+
+            let label = match sharpness[index] {
+                Some(value) if max_sharpness > 0.0 => {
+                    format!("{} · {:.0}%", index, value / max_sharpness * 100.0)
+                }
+                _ => index.to_string(),
+            };
+
+            let content = Box::builder()
+                .orientation(Orientation::Vertical)
+                .spacing(2)
+                .build();
+            content.append(&thumbnail);
+            content.append(&Label::new(Some(&label)));
+
+            let button = Button::builder()
+                .child(&content)
+                .css_classes(vec!["flat".to_string()])
+                .focus_on_click(false)
+                .build();
+            let _sender = self.sender.clone();
+            button.connect_clicked(move |_| {
+                _sender.send(Message::FocusLevelChange(index)).unwrap();
+            });
+
+            self.filmstrip.append(&button);
+            self.filmstrip_buttons.borrow_mut().push(button);
+        }
+    }
+
+    fn get_sharpness(&self, path: &Path, page: Option<usize>) -> Option<f64> {
+        let key = (path.to_path_buf(), page);
+        if let Some(sharpness) = self.sharpness_cache.borrow().get(&key) {
+            return Some(*sharpness);
+        }
+
+        let plane = match self.plane_cache.borrow_mut().get(path, page) {
+            Ok(plane) => plane,
+            Err(e) => {
+                eprintln!("Could not load image {}", e);
+                return None;
+            }
+        };
+        let sharpness = plane.sharpness();
+        self.sharpness_cache.borrow_mut().insert(key, sharpness);
+        Some(sharpness)
+    }
+
+    // Estimates the drift of the planes of the shown stack once it is drawn, from the planes the
+    // views already loaded. The state stores it when `Message::PlaneShifts` arrives.
+    fn register_stack(&self, state: &State) {
+        let (stack_index, stack, root_path) = match (
+            state.needs_registration(),
+            state.get_current_focus_stack(),
+            state.root_path.clone(),
+        ) {
+            (Some(stack_index), Some(stack), Some(root_path)) => (stack_index, stack, root_path),
+            (_, _, _) => return,
+        };
+        if self.registering.get() {
+            return;
+        }
+        self.registering.set(true);
+
+        let planes: Vec<(PathBuf, Option<usize>)> = stack
+            .images
+            .iter()
+            .map(|image| (Path::new(&root_path).join(&image.image_path), image.page))
+            .collect();
+        let plane_cache = self.plane_cache.clone();
+        let _sender = self.sender.clone();
+        idle_add_local_once(move || {
+            let shifts = planes
+                .iter()
+                .map(|(path, page)| plane_cache.borrow_mut().get(path, *page))
+                .collect::<Result<Vec<Rc<Plane>>, String>>()
+                .and_then(|planes| {
+                    register_planes(&planes, planes.len() / 2)
+                        .ok_or_else(|| "the planes differ in size".to_string())
+                });
+            _sender
+                .send(Message::PlaneShifts(stack_index, shifts))
+                .unwrap();
+        });
+    }
+
+    // Plane of the current stack, shifted onto the reference plane if planes are aligned
+    fn load_stack_plane(
+        &self,
+        image: &AnnotationImage,
+        base_path: &str,
+        state: &State,
+    ) -> Option<Plane> {
+        let path = Path::new(base_path).join(Path::new(&image.image_path));
+        match self.plane_cache.borrow_mut().get(&path, image.page) {
+            Ok(plane) => {
+                Some(ImageUI::aligned(&plane, image, state).adjusted(state.get_display_settings()))
+            }
+            Err(e) => {
+                eprintln!("Could not load image {}", e);
+                None
+            }
+        }
+    }
+
+    fn aligned(plane: &Plane, image: &AnnotationImage, state: &State) -> Plane {
+        match (&image.transform, state.is_aligning_planes()) {
+            (Some(transform), true) => plane.translated(transform.dx, transform.dy),
+            (_, _) => plane.clone(),
+        }
+    }
+
+    fn load_pixbuf(
+        &self,
+        path: &Path,
+        page: Option<usize>,
+        display_settings: &DisplaySettings,
+    ) -> Option<Pixbuf> {
+        self.load_plane(path, page, display_settings)
+            .map(|plane| ImageUI::plane_to_pixbuf(&plane))
+    }
+
+    fn load_plane(
+        &self,
+        path: &Path,
+        page: Option<usize>,
+        display_settings: &DisplaySettings,
+    ) -> Option<Plane> {
+        match self.plane_cache.borrow_mut().get(path, page) {
+            Ok(plane) => Some(plane.adjusted(display_settings)),
+            Err(e) => {
+                eprintln!("Could not load image {}", e);
+                None
+            }
+        }
+    }
+
+    fn plane_to_pixbuf(plane: &Plane) -> Pixbuf {
+        Pixbuf::from_bytes(
+            &Bytes::from_owned(plane.to_rgb8()),
+            Colorspace::Rgb,
+            false,
+            8,
+            plane.width as i32,
+            plane.height as i32,
+            plane.width as i32 * 3,
+        )
+    }
+
+    fn update_focus_scale(&self, state: &State) {
+        let max = state.get_current_foucs_stack_max().unwrap_or(0) as f64;
+        self.focus_scale.set_range(0.0, max);
+
+        if let Some(best_index) = state.get_current_foucs_stack_best_index() {
+            let label = match state
+                .get_current_focus_stack()
+                .and_then(|stack| stack.best_z)
+            {
+                Some(best_z) => format!("focus (z {})", best_z),
+                None => "focus".to_string(),
+            };
+            self.focus_scale.clear_marks();
+            self.focus_scale
+                .add_mark(best_index as f64, PositionType::Right, Some(&label));
+            self.focus_scale.set_margin_end(0);
+        } else {
+            self.focus_scale.clear_marks();
+            self.focus_scale
+                .set_margin_end(MARGIN_RIGHT_SCALE_ADDITIONAL);
+        }
+
+        if let Some(pinned_index) = state.get_pinned_image_index() {
+            self.focus_scale
+                .add_mark(pinned_index as f64, PositionType::Left, Some("pin"));
+        }
+
+        let sharpest_index = match state.is_sharpest_focus_pending() {
+            true => self.get_sharpest_index(state),
+            false => None,
+        };
+        if let Some(current_value) = sharpest_index.or(state.get_focus_image_index()) {
+            self.focus_scale.set_value(current_value as f64);
+        } else {
+            self.focus_scale.set_value(f64::floor(max / 2.0));
+        }
+
+        self.update_z_subtitle(state);
+    }
+
+    fn jump_focus(&self, jump: FocusJump, state: &State) {
+        let max = match state.get_current_foucs_stack_max() {
+            Some(max) => max as f64,
+            None => return,
+        };
+        let value = self.focus_scale.value();
+        let target = match jump {
+            FocusJump::First => Some(0.0),
+            FocusJump::Last => Some(max),
+            FocusJump::PageUp => Some(value + FOCUS_PAGE_STEP),
+            FocusJump::PageDown => Some(value - FOCUS_PAGE_STEP),
+            FocusJump::Sharpest => self.get_sharpest_index(state).map(|x| x as f64),
+            FocusJump::Marked => state.get_current_foucs_stack_best_index().map(|x| x as f64),
+        };
+        if let Some(target) = target {
+            self.focus_scale.set_value(target.clamp(0.0, max));
+        }
+    }
+
+    // Digits typed in quick succession form one index, an index beyond the stack starts over
+    fn focus_digit(&self, digit: u32, state: &State) {
+        let max = match state.get_current_foucs_stack_max() {
+            Some(max) => max,
+            None => return,
+        };
+        let now = Instant::now();
+        let (typed, last_typed) = self.focus_digits.get();
+        let continues = last_typed.map_or(false, |x| {
+            now.duration_since(x) < Duration::from_millis(FOCUS_DIGIT_TIMEOUT_MS)
+        });
+        let mut index = match continues {
+            true => typed.saturating_mul(10).saturating_add(digit as usize),
+            false => digit as usize,
+        };
+        if index > max {
+            index = digit as usize;
+        }
+        self.focus_digits.set((index, Some(now)));
+        self.focus_scale.set_value(index.min(max) as f64);
+    }
+
+    // Index of the plane with the highest variance of the Laplacian in the current stack
+    fn get_sharpest_index(&self, state: &State) -> Option<usize> {
+        let (stack, base_path) = match (state.get_current_focus_stack(), state.root_path.clone()) {
+            (Some(stack), Some(base_path)) => (stack, base_path),
+            (_, _) => return None,
+        };
+        stack
+            .images
+            .iter()
+            .enumerate()
+            .filter_map(|(index, image)| {
+                let path = Path::new(&base_path).join(Path::new(&image.image_path));
+                self.get_sharpness(&path, image.page)
+                    .map(|sharpness| (index, sharpness))
+            })
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(index, _)| index)
+    }
+
+    fn update_z_subtitle(&self, state: &State) {
+        let z = state.get_current_z();
+        let z_offset = state
+            .get_current_focus_stack()
+            .and_then(|stack| stack.get_z_offset(z));
+
+        let subtitle = match (z, z_offset) {
+            (Some(z), Some(z_offset)) => format!("z {} ({:+})", z, z_offset),
+            (Some(z), None) => format!("z {}", z),
+            (_, _) => "".to_string(),
+        };
+        let subtitle = match (state.is_showing_pinned(), state.get_pinned_image_index()) {
+            (true, Some(pinned_index)) => format!("showing pinned level {}", pinned_index),
+            (_, _) => subtitle,
+        };
+        self.window_title.set_subtitle(&subtitle);
+    }
+
+    // Adds or removes tab pages to match the open datasets, titles them and moves the image view
+    // into the selected one
+    pub fn update_tabs(&self, tabs: &Tabs) {
+        self.tabs_updating.set(true);
+
+        let count = tabs.states().len();
+        while (self.tab_view.n_pages() as usize) < count {
+            self.tab_view.append(&Box::new(Orientation::Horizontal, 0));
+        }
+
+        let selected_page = self.tab_view.nth_page(tabs.selected() as i32);
+        if let Ok(page_box) = selected_page.child().downcast::<Box>() {
+            let parent = self.center_content.parent();
+            if parent.as_ref() != Some(page_box.upcast_ref::<gtk::Widget>()) {
+                if let Some(parent) = parent.and_then(|x| x.downcast::<Box>().ok()) {
+                    parent.remove(self.center_content.as_ref());
+                }
+                page_box.append(self.center_content.as_ref());
+            }
+        }
+
+        // Pages are interchangeable, surplus ones are removed from the end
+        while (self.tab_view.n_pages() as usize) > count {
+            let last_page = self.tab_view.nth_page(self.tab_view.n_pages() - 1);
+            self.tab_view.close_page(&last_page);
+        }
+
+        for (index, state) in tabs.states().iter().enumerate() {
+            let page = self.tab_view.nth_page(index as i32);
+            match (state.get_file_name(), state.get_file_path()) {
+                (Some(file_name), Some(path)) => {
+                    let (annotated, total) = state.get_progress();
+                    page.set_title(&format!(
+                        "{}{}{} ({}/{})",
+                        ImageUI::dirty_indicator(state),
+                        file_name,
+                        ImageUI::read_only_indicator(state),
+                        annotated,
+                        total
+                    ));
+                    let mut tooltip = path.to_string_lossy().to_string();
+                    if let Some(save_path) = state.get_save_path().filter(|x| *x != path) {
+                        tooltip.push_str(&format!("\nSaved to {}", save_path.display()));
+                    }
+                    if let Some(holder) = state.get_lock_holder() {
+                        tooltip.push_str(&format!("\nOpened by {}", holder.describe()));
+                    }
+                    page.set_tooltip(&tooltip);
+                }
+                (_, _) => {
+                    page.set_title(EMPTY_TAB_TITLE);
+                    page.set_tooltip("");
+                }
+            }
+        }
+        self.tab_view.set_selected_page(&selected_page);
+
+        let title = match tabs.current().get_file_name() {
+            Some(file_name) => format!(
+                "{}{}{} – {}",
+                ImageUI::dirty_indicator(tabs.current()),
+                file_name,
+                ImageUI::read_only_indicator(tabs.current()),
+                APPLICATION_TITLE
+            ),
+            None => APPLICATION_TITLE.to_string(),
+        };
+        self.window_title.set_title(&title);
+
+        self.tabs_updating.set(false);
+    }
+
+    fn dirty_indicator(state: &State) -> &'static str {
+        match state.is_dirty() {
+            true => DIRTY_INDICATOR,
+            false => "",
+        }
+    }
+
+    fn read_only_indicator(state: &State) -> &'static str {
+        match state.is_read_only() {
+            true => READ_ONLY_INDICATOR,
+            false => "",
+        }
+    }
+
+    fn clear_images(&self) {
+        for image in [
+            &self.individual,
+            &self.center,
+            &self.mosaic_image,
+            &self.pinned_image,
+            &self.comparison_image,
+            &self.all_in_focus_current_image,
+            &self.all_in_focus_image,
+        ] {
+            image.set_from_pixbuf(None);
+        }
+        for (_, image) in self.neighbours.borrow().iter() {
+            image.set_from_pixbuf(None);
+        }
+    }
+
+    // Lists the opened dataset first in the recent files with its progress, see `save_recent_files`
+    fn update_recent_files(&self, state: &State) {
+        if let Some(path) = state.get_file_path() {
+            let (annotated, total) = state.get_progress();
+            RecentFile::add_to(&mut self.recent_files.borrow_mut(), &path, annotated, total);
+        }
+        ImageUI::fill_recent_menu(&self.recent_menu, &self.recent_files.borrow());
+    }
+
+    fn save_recent_files(&self) {
+        let mut config = Config::load();
+        config.recent_files = self.recent_files.borrow().clone();
+        if let Err(e) = config.save() {
+            eprintln!("Could not save config: {}", e);
+        }
+    }
+
+    fn fill_recent_menu(menu: &Menu, recent_files: &[RecentFile]) {
+        menu.remove_all();
+        for recent_file in recent_files.iter().filter(|x| x.path.exists()) {
+            let name = recent_file
+                .path
+                .file_name()
+                .map_or("".into(), |x| x.to_string_lossy());
+            let label = format!("{} ({}/{})", name, recent_file.annotated, recent_file.total);
+            let item = MenuItem::new(Some(&label), None);
+            item.set_action_and_target_value(
+                Some("win.open_recent"),
+                Some(&recent_file.path.to_string_lossy().to_variant()),
+            );
+            menu.append_item(&item);
+        }
+    }
+
+    // Settings that can also be changed in the main window are only taken over when the
+    // application starts, so changing another preference does not reset them
+    pub fn apply_start_preferences(&self, preferences: &Preferences) {
+        self.skip_marked_checkbox
+            .set_active(preferences.skip_marked);
+        self.neighbour_toggle_button
+            .set_active(preferences.show_grid);
+    }
+
+    // Applies the preferences that changed since they were last applied
+    fn apply_preferences(&self, preferences: &Preferences) {
+        let previous = self.applied_preferences.replace(Some(preferences.clone()));
+        let previous = previous.as_ref();
+
+        if previous.map(|x| x.cache_size) != Some(preferences.cache_size) {
+            self.plane_cache
+                .borrow_mut()
+                .set_capacity(preferences.cache_size);
+        }
+        if previous.map(|x| x.theme) != Some(preferences.theme) {
+            StyleManager::default().set_color_scheme(match preferences.theme {
+                Theme::System => ColorScheme::Default,
+                Theme::Light => ColorScheme::ForceLight,
+                Theme::Dark => ColorScheme::ForceDark,
+            });
+        }
+        if previous.map(|x| x.autosave_interval) == Some(preferences.autosave_interval) {
+            return;
+        }
+        if let Some(source_id) = self.autosave_source.borrow_mut().take() {
+            source_id.remove();
+        }
+        if preferences.autosave_interval > 0 {
+            let _sender = self.sender.clone();
+            let source_id = timeout_add_seconds_local(preferences.autosave_interval, move || {
+                _sender.send(Message::Autosave).unwrap();
+                Continue(true)
+            });
+            self.autosave_source.replace(Some(source_id));
+        }
+    }
+
+    // Stores a changed preference and applies it to all tabs
+    fn change_preferences<F: FnOnce(&mut Preferences)>(sender: &Sender<Message>, change: F) {
+        let mut config = Config::load();
+        change(&mut config.preferences);
+        if let Err(e) = config.save() {
+            eprintln!("Could not save config: {}", e);
+        }
+        sender
+            .send(Message::PreferencesChanged(config.preferences))
+            .unwrap();
+    }
+
+    fn show_preferences(&self) {
+        let preferences = Config::load().preferences;
+
+        let skip_marked_switch = Switch::builder()
+            .active(preferences.skip_marked)
+            .valign(Align::Center)
+            .build();
+        let _sender = self.sender.clone();
+        skip_marked_switch.connect_active_notify(move |x| {
+            let value = x.is_active();
+            ImageUI::change_preferences(&_sender, |preferences| preferences.skip_marked = value);
+        });
+        let skip_marked_row = ActionRow::builder()
+            .title("Skip marked focus stacks")
+            .subtitle("When the application starts")
+            .activatable_widget(&skip_marked_switch)
+            .build();
+        skip_marked_row.add_suffix(&skip_marked_switch);
+
+        let show_grid_switch = Switch::builder()
+            .active(preferences.show_grid)
+            .valign(Align::Center)
+            .build();
+        let _sender = self.sender.clone();
+        show_grid_switch.connect_active_notify(move |x| {
+            let value = x.is_active();
+            ImageUI::change_preferences(&_sender, |preferences| preferences.show_grid = value);
+        });
+        let show_grid_row = ActionRow::builder()
+            .title("Show neighbours")
+            .subtitle("When the application starts")
+            .activatable_widget(&show_grid_switch)
+            .build();
+        show_grid_row.add_suffix(&show_grid_switch);
+
+        let initial_focus_labels: Vec<&str> = InitialFocus::ALL.iter().map(|x| x.label()).collect();
+        let initial_focus_row = ComboRow::builder()
+            .title("Initial focus level")
+            .subtitle("Annotated focus stacks start at their marked level")
+            .model(&StringList::new(&initial_focus_labels))
+            .selected(
+                InitialFocus::ALL
+                    .iter()
+                    .position(|x| *x == preferences.initial_focus)
+                    .unwrap_or(0) as u32,
+            )
+            .build();
+        let _sender = self.sender.clone();
+        initial_focus_row.connect_selected_notify(move |x| {
+            if let Some(initial_focus) = InitialFocus::ALL.get(x.selected() as usize) {
+                let initial_focus = *initial_focus;
+                ImageUI::change_preferences(&_sender, |preferences| {
+                    preferences.initial_focus = initial_focus
+                });
+            }
+        });
+
+        let annotation_group = PreferencesGroup::builder().title("Annotation").build();
+        annotation_group.add(&skip_marked_row);
+        annotation_group.add(&show_grid_row);
+        annotation_group.add(&initial_focus_row);
+
+        let separate_output_switch = Switch::builder()
+            .active(preferences.separate_output)
+            .valign(Align::Center)
+            .build();
+        let _sender = self.sender.clone();
+        separate_output_switch.connect_active_notify(move |x| {
+            let value = x.is_active();
+            ImageUI::change_preferences(&_sender, |preferences| {
+                preferences.separate_output = value
+            });
+        });
+        let separate_output_row = ActionRow::builder()
+            .title("Save to a separate file")
+            .subtitle("Annotations go to a file named after the dataset and the user")
+            .activatable_widget(&separate_output_switch)
+            .build();
+        separate_output_row.add_suffix(&separate_output_switch);
+
+        let output_dir_row = ActionRow::builder()
+            .title("Output folder")
+            .subtitle(&ImageUI::output_dir_label(&preferences))
+            .sensitive(preferences.separate_output)
+            .build();
+        separate_output_switch
+            .bind_property("active", &output_dir_row, "sensitive")
+            .build();
+        let output_dir_button = Button::builder()
+            .icon_name("folder-open-symbolic")
+            .valign(Align::Center)
+            .build();
+        let _sender = self.sender.clone();
+        let _output_dir_row = output_dir_row.clone();
+        let _window = self.window.clone();
+        output_dir_button.connect_clicked(move |_| {
+            let buttons = [
+                ("Select", ResponseType::Ok),
+                ("Cancel", ResponseType::Cancel),
+            ];
+            let folder_chooser = FileChooserDialog::new(
+                Some("Chose the output folder"),
+                Some(_window.as_ref()),
+                FileChooserAction::SelectFolder,
+                &buttons,
+            );
+            let _sender = _sender.clone();
+            let _output_dir_row = _output_dir_row.clone();
+            folder_chooser.connect_response(
+                move |dialog: &FileChooserDialog, response: ResponseType| {
+                    if response == ResponseType::Ok {
+                        if let Some(path) = dialog.file().and_then(|x| x.path()) {
+                            ImageUI::change_preferences(&_sender, |preferences| {
+                                preferences.output_dir = Some(path)
+                            });
+                            _output_dir_row.set_subtitle(&ImageUI::output_dir_label(
+                                &Config::load().preferences,
+                            ));
+                        }
+                    }
+                    dialog.close();
+                },
+            );
+            folder_chooser.show();
+        });
+        let output_dir_reset_button = Button::builder()
+            .icon_name("edit-clear-symbolic")
+            .tooltip_text("Save next to the dataset")
+            .valign(Align::Center)
+            .build();
+        let _sender = self.sender.clone();
+        let _output_dir_row = output_dir_row.clone();
+        output_dir_reset_button.connect_clicked(move |_| {
+            ImageUI::change_preferences(&_sender, |preferences| preferences.output_dir = None);
+            _output_dir_row.set_subtitle(&ImageUI::output_dir_label(&Config::load().preferences));
+        });
+        output_dir_row.add_suffix(&output_dir_button);
+        output_dir_row.add_suffix(&output_dir_reset_button);
+
+        let output_group = PreferencesGroup::builder()
+            .title("Output")
+            .description("Applies to datasets opened afterwards")
+            .build();
+        output_group.add(&separate_output_row);
+        output_group.add(&output_dir_row);
+
+        let theme_labels: Vec<&str> = Theme::ALL.iter().map(|x| x.label()).collect();
+        let theme_row = ComboRow::builder()
+            .title("Theme")
+            .model(&StringList::new(&theme_labels))
+            .selected(
+                Theme::ALL
+                    .iter()
+                    .position(|x| *x == preferences.theme)
+                    .unwrap_or(0) as u32,
+            )
+            .build();
+        let _sender = self.sender.clone();
+        theme_row.connect_selected_notify(move |x| {
+            if let Some(theme) = Theme::ALL.get(x.selected() as usize) {
+                let theme = *theme;
+                ImageUI::change_preferences(&_sender, |preferences| preferences.theme = theme);
+            }
+        });
+
+        let cache_size_button = SpinButton::with_range(1.0, 1000.0, 1.0);
+        cache_size_button.set_value(preferences.cache_size as f64);
+        cache_size_button.set_valign(Align::Center);
+        let _sender = self.sender.clone();
+        cache_size_button.connect_value_changed(move |x| {
+            let value = x.value_as_int() as usize;
+            ImageUI::change_preferences(&_sender, |preferences| preferences.cache_size = value);
+        });
+        let cache_size_row = ActionRow::builder()
+            .title("Image cache size")
+            .subtitle("Number of decoded images kept in memory")
+            .build();
+        cache_size_row.add_suffix(&cache_size_button);
+
+        let autosave_button = SpinButton::with_range(0.0, 3600.0, 10.0);
+        autosave_button.set_value(preferences.autosave_interval as f64);
+        autosave_button.set_valign(Align::Center);
+        let _sender = self.sender.clone();
+        autosave_button.connect_value_changed(move |x| {
+            let value = x.value_as_int() as u32;
+            ImageUI::change_preferences(&_sender, |preferences| {
+                preferences.autosave_interval = value
+            });
+        });
+        let autosave_row = ActionRow::builder()
+            .title("Autosave interval")
+            .subtitle("Seconds between saves of unsaved changes, 0 turns autosave off")
+            .build();
+        autosave_row.add_suffix(&autosave_button);
+
+        let application_group = PreferencesGroup::builder().title("Application").build();
+        application_group.add(&theme_row);
+        application_group.add(&cache_size_row);
+        application_group.add(&autosave_row);
+
+        let page = PreferencesPage::new();
+        page.add(&annotation_group);
+        page.add(&output_group);
+        page.add(&application_group);
+
+        let preferences_window = PreferencesWindow::builder()
+            .transient_for(self.window.as_ref())
+            .modal(true)
+            .build();
+        preferences_window.add(&page);
+        preferences_window.show();
+    }
+
+    fn output_dir_label(preferences: &Preferences) -> String {
+        match &preferences.output_dir {
+            Some(output_dir) => output_dir.to_string_lossy().to_string(),
+            None => "Next to the dataset".to_string(),
+        }
+    }
+
+    pub fn setup_shortcuts(app: &Application) {
+        ImageUI::apply_shortcuts(app, &Config::load());
+    }
+
+    fn apply_shortcuts<A: GtkApplicationExt>(app: &A, config: &Config) {
+        for (action, _, _) in SHORTCUTS.iter() {
+            let accelerator = config.get_shortcut(action);
+            let accelerators: Vec<&str> = match accelerator.as_str() {
+                "" => vec![],
+                accelerator => vec![accelerator],
+            };
+            app.set_accels_for_action(&format!("win.{}", action), &accelerators);
+        }
+    }
+
+    // Digit a key jumps to a z index with, keys combined with a command modifier are shortcuts
+    pub fn focus_digit(key: Key, modifiers: ModifierType) -> Option<u32> {
+        let command_modifiers =
+            ModifierType::CONTROL_MASK | ModifierType::ALT_MASK | ModifierType::SUPER_MASK;
+        match modifiers.intersects(command_modifiers) {
+            true => None,
+            false => key.to_unicode().and_then(|x| x.to_digit(10)),
+        }
+    }
+
+    // Problem of every shortcut that can not be used: invalid, bound twice or reserved
+    fn shortcut_problems(accelerators: &[String]) -> Vec<Option<String>> {
+        let parsed: Vec<Option<(Key, ModifierType)>> = accelerators
+            .iter()
+            .map(|x| gtk::accelerator_parse(x.trim()))
+            .collect();
+        let compare_hold_key = Key::from_name(COMPARE_HOLD_KEY);
+
+        accelerators
+            .iter()
+            .enumerate()
+            .map(|(index, accelerator)| {
+                if accelerator.trim().is_empty() {
+                    return None;
+                }
+                let shortcut = match parsed[index] {
+                    Some(shortcut) => shortcut,
+                    None => return Some(format!("\"{}\" is not a valid shortcut", accelerator)),
+                };
+                if Some(shortcut.0) == compare_hold_key && shortcut.1.is_empty() {
+                    return Some(format!("\"{}\" is used to compare", accelerator));
+                }
+                if ImageUI::focus_digit(shortcut.0, shortcut.1).is_some() {
+                    return Some(format!(
+                        "\"{}\" is used to go to a focus level",
+                        accelerator
+                    ));
+                }
+                parsed
+                    .iter()
+                    .enumerate()
+                    .find(|(other, x)| *other != index && **x == Some(shortcut))
+                    .map(|(other, _)| {
+                        format!(
+                            "\"{}\" is also used for \"{}\"",
+                            accelerator, SHORTCUTS[other].1
+                        )
+                    })
+            })
+            .collect()
+    }
+
+    fn show_shortcuts_dialog(&self) {
+        let buttons = [
+            ("Apply", ResponseType::Ok),
+            ("Cancel", ResponseType::Cancel),
+        ];
+        let dialog = Dialog::with_buttons(
+            Some("Keyboard Shortcuts"),
+            Some(self.window.as_ref()),
+            DialogFlags::MODAL | DialogFlags::DESTROY_WITH_PARENT,
+            &buttons,
+        );
+
+        let config = Config::load();
+        let shortcuts = Grid::builder().column_spacing(12).row_spacing(6).build();
+        let entries: Vec<Entry> = SHORTCUTS
+            .iter()
+            .enumerate()
+            .map(|(row, (action, description, _))| {
+                let entry = Entry::builder()
+                    .text(&config.get_shortcut(action))
+                    .placeholder_text("disabled")
+                    .build();
+                let label = Label::builder().label(description).xalign(0.0).build();
+                shortcuts.attach(&label, 0, row as i32, 1, 1);
+                shortcuts.attach(&entry, 1, row as i32, 1, 1);
+                entry
+            })
+            .collect();
+        let problem_label = Label::builder()
+            .css_classes(vec!["error".to_string()])
+            .build();
+
+        let content = dialog.content_area();
+        content.set_spacing(12);
+        content.set_margin_top(MARGIN_TOP);
+        content.set_margin_bottom(MARGIN_BOTTOM);
+        content.set_margin_start(MARGIN_LEFT);
+        content.set_margin_end(MARGIN_LEFT);
+        content.append(&Label::new(Some(
+            "Shortcuts are written like W, <Control>s or Page_Down, leave empty to disable",
+        )));
+        content.append(&shortcuts);
+        content.append(&problem_label);
+
+        let entries = Rc::new(entries);
+        let _entries = entries.clone();
+        let _dialog = dialog.clone();
+        let validate = Rc::new(move || {
+            let accelerators: Vec<String> = _entries.iter().map(|x| x.text().to_string()).collect();
+            let problems = ImageUI::shortcut_problems(&accelerators);
+            for (entry, problem) in _entries.iter().zip(problems.iter()) {
+                if problem.is_some() {
+                    entry.add_css_class("error");
+                } else {
+                    entry.remove_css_class("error");
+                }
+            }
+            let first_problem = problems.iter().flatten().next();
+            problem_label.set_text(first_problem.map_or("", |x| x.as_str()));
+            _dialog.set_response_sensitive(ResponseType::Ok, first_problem.is_none());
+        });
+        for entry in entries.iter() {
+            let _validate = validate.clone();
+            entry.connect_changed(move |_| _validate());
+        }
+        validate();
+
+        let window = self.window.clone();
+        dialog.connect_response(move |dialog: &Dialog, response: ResponseType| {
+            if response == ResponseType::Ok {
+                let mut config = Config::load();
+                for ((action, _, _), entry) in SHORTCUTS.iter().zip(entries.iter()) {
+                    config.set_shortcut(action, entry.text().trim());
+                }
+                if let Err(e) = config.save() {
+                    eprintln!("Could not save config: {}", e);
+                }
+                if let Some(app) = window.application() {
+                    ImageUI::apply_shortcuts(&app, &config);
+                }
+            }
+            dialog.close();
+        });
+
+        dialog.show();
     }
 }